@@ -0,0 +1,94 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Slash commands handled by the client itself                     #
+//********************************************************************
+
+use shared::{CommandHelp, SERVER_COMMANDS};
+
+// Constants
+pub const COMMAND_PREFIX: &str = "/";
+pub const CONFIRM_COMMAND: &str = "/yes";
+
+/// Every command handled by the client itself, including CONFIRM_COMMAND
+pub const LOCAL_COMMANDS: [CommandHelp; 8] = [
+    CommandHelp{ name: "/help", args: "[command]", description: "lists every command, or shows how to use one of them" },
+    CommandHelp{ name: "/dnd", args: "", description: "toggles do-not-disturb mode" },
+    CommandHelp{ name: "/count", args: "", description: "toggles showing the length of each message" },
+    CommandHelp{ name: "/save", args: "<path>", description: "saves the chat history, as JSON lines if the path ends in .json or .jsonl" },
+    CommandHelp{ name: "/clear", args: "", description: "clears the chat history, for you only" },
+    CommandHelp{ name: "/reconnect", args: "", description: "connects to the server again, keeping the history" },
+    CommandHelp{ name: "/quit", args: "", description: "leaves the chat" },
+    CommandHelp{ name: CONFIRM_COMMAND, args: "", description: "confirms a command listed in CONFIRM_COMMANDS" },
+];
+
+/// Commands which only affect the local client and are never sent to the server
+pub enum LocalCommand {
+    /// Toggles do-not-disturb mode
+    DoNotDisturb,
+    /// Toggles showing each message's length in its metadata
+    Count,
+    /// Saves the chat history to the file at the given path
+    Save(String),
+    /// Clears the chat history, for this client only
+    Clear,
+    /// Connects to the server again, as the same user in the same room
+    Reconnect,
+    /// Leaves the chat
+    Quit,
+    /// Lists the commands, or shows the one given
+    Help(String),
+    /// Shows how fast the chat draws and how much is waiting in its channels. Not listed by "/help", as it is
+    /// meant for diagnosing a sluggish chat
+    DebugStats,
+}
+impl LocalCommand {
+    /// Parses "input" into a local command, if it is one
+    pub fn parse(input: &str) -> Option<Self> {
+        let (command, args) = input.split_once(' ').unwrap_or((input, ""));
+
+        match command {
+            "/dnd" => Some(Self::DoNotDisturb),
+            "/count" => Some(Self::Count),
+            "/save" => Some(Self::Save(args.trim().to_string())),
+            "/clear" => Some(Self::Clear),
+            "/reconnect" => Some(Self::Reconnect),
+            "/quit" => Some(Self::Quit),
+            "/help" => Some(Self::Help(args.trim().to_string())),
+            "/debugstats" => Some(Self::DebugStats),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the name of the command in "input", i.e. "/join" for "/join dev", if it is one
+pub fn command_name(input: &str) -> Option<&str> {
+    input
+        .starts_with(COMMAND_PREFIX)
+        .then(|| input.split_whitespace().next().unwrap_or(input))
+}
+
+/// Describes the command named "topic", with or without its prefix, or every command if "topic" is empty
+/// Commands handled by the client itself are marked as such
+pub fn help(topic: &str) -> String {
+    let describe = |command: &CommandHelp, local: bool| {
+        let usage = if command.args.is_empty() {command.name.to_string()} else {format!("{} {}", command.name, command.args)};
+        format!("{usage}: {}{}", command.description, if local {" (local)"} else {""})
+    };
+    let mut commands = LOCAL_COMMANDS.iter().map(|command| (command, true)).chain(SERVER_COMMANDS.iter().map(|command| (command, false)));
+
+    if topic.is_empty() {
+        let lines: Vec<String> = commands.map(|(command, local)| describe(command, local)).collect();
+        return format!("Commands are run by the server, unless marked local:\n{}", lines.join("\n"));
+    }
+
+    let name = format!("{COMMAND_PREFIX}{}", topic.trim_start_matches(COMMAND_PREFIX));
+    match commands.find(|(command, _)| command.name == name) {
+        Some((command, local)) => describe(command, local),
+        None => format!("There is no {name} command. /help lists them all"),
+    }
+}
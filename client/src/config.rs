@@ -0,0 +1,331 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   The client configuration, read from environment variables       #
+//********************************************************************
+
+use std::{path::PathBuf, time::{Duration, SystemTime}};
+
+use chrono::{format::{Item, StrftimeItems}, DateTime, Local, Utc};
+use chrono_tz::Tz;
+use shared::{validate_color, validate_room_name, ClientMessage, Invite, DEFAULT_ROOM};
+
+use crate::{commands::COMMAND_PREFIX, terminal::TerminalFeatures};
+
+// Constants
+const DEFAULT_SERVER_URL: &str = "ws://127.0.0.1:5050";
+const DEFAULT_TIME_FORMAT: &str = "%H:%M";
+const DEFAULT_KEEPALIVE_IDLE_SECS: u64 = 60;
+const DEFAULT_KEEPALIVE_INTERVAL_SECS: u64 = 10;
+const DEFAULT_HISTORY_SIZE: usize = 500;
+const DEFAULT_INPUT_MAX_LINES: usize = 5;
+const DEFAULT_SCROLL_PAUSE_SECS: u64 = 3;
+const HISTORY_FILE_NAME: &str = "chatey/history";
+
+/// The timezone absolute timestamps are displayed in
+pub enum TimeZoneSetting {
+    Local,
+    Utc,
+    Named(Tz),
+}
+
+/// How message timestamps are displayed
+pub enum TimestampStyle {
+    /// i.e. "5 min ago"
+    Relative,
+    /// A wall-clock time, i.e. "14:05"
+    Absolute { zone: TimeZoneSetting, format: String },
+}
+impl TimestampStyle {
+    /// Reads the style from TIMESTAMPS, TIMEZONE and TIME_FORMAT
+    /// Invalid values are logged and replaced by the defaults
+    fn from_env() -> Self {
+        match std::env::var("TIMESTAMPS").as_deref() {
+            Ok("absolute") => {}
+            Ok("relative") | Err(_) => return Self::Relative,
+            Ok(other) => {
+                log::warn!("Unknown TIMESTAMPS \"{other}\", expected \"relative\" or \"absolute\"");
+                return Self::Relative;
+            }
+        }
+
+        let zone = match std::env::var("TIMEZONE").as_deref() {
+            Ok("local") | Err(_) => TimeZoneSetting::Local,
+            Ok("utc") | Ok("UTC") => TimeZoneSetting::Utc,
+            Ok(name) => match name.parse::<Tz>() {
+                Ok(tz) => TimeZoneSetting::Named(tz),
+                Err(_) => {
+                    log::warn!("Unknown TIMEZONE \"{name}\". Using local time");
+                    TimeZoneSetting::Local
+                }
+            },
+        };
+
+        // An invalid format would otherwise panic when displayed
+        let format = match std::env::var("TIME_FORMAT") {
+            Ok(format) if StrftimeItems::new(&format).any(|item| item == Item::Error) => {
+                log::warn!("Invalid TIME_FORMAT \"{format}\". Using \"{DEFAULT_TIME_FORMAT}\"");
+                DEFAULT_TIME_FORMAT.to_string()
+            }
+            Ok(format) => format,
+            Err(_) => DEFAULT_TIME_FORMAT.to_string(),
+        };
+
+        Self::Absolute { zone, format }
+    }
+
+    /// Returns a pretty string containing user and timestamp, in this style
+    pub fn metadata(&self, message: &ClientMessage) -> String {
+        match self {
+            Self::Relative => message.get_metadata(),
+            Self::Absolute { zone, format } => {
+                let sent_at = SystemTime::now()
+                    .checked_sub(message.get_timestamp().elapsed())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                let sent_at: DateTime<Utc> = sent_at.into();
+
+                let time = match zone {
+                    TimeZoneSetting::Local => sent_at.with_timezone(&Local).format(format).to_string(),
+                    TimeZoneSetting::Utc => sent_at.format(format).to_string(),
+                    TimeZoneSetting::Named(tz) => sent_at.with_timezone(tz).format(format).to_string(),
+                };
+                format!("{}, {time}", message.get_username())
+            }
+        }
+    }
+}
+
+/// Which messages run NOTIFY_COMMAND
+#[derive(PartialEq)]
+pub enum NotifyOn {
+    /// Any message arriving while away
+    Any,
+    /// Only messages mentioning this user as "@username"
+    Mentions,
+}
+impl NotifyOn {
+    /// Reads the setting from NOTIFY_ON, logging and ignoring invalid values
+    fn from_env() -> Self {
+        match std::env::var("NOTIFY_ON").as_deref() {
+            Ok("any") | Err(_) => Self::Any,
+            Ok("mentions") => Self::Mentions,
+            Ok(other) => {
+                log::warn!("Unknown NOTIFY_ON \"{other}\", expected \"any\" or \"mentions\"");
+                Self::Any
+            }
+        }
+    }
+}
+
+/// How the cursor is drawn in the input boxes
+#[derive(Clone, Copy)]
+pub enum CursorShape {
+    Underscore,
+    Block,
+    Pipe,
+}
+impl CursorShape {
+    /// Reads the shape from CURSOR_SHAPE, logging and ignoring invalid values
+    fn from_env() -> Self {
+        match std::env::var("CURSOR_SHAPE").as_deref() {
+            Ok("underscore") | Err(_) => Self::Underscore,
+            Ok("block") => Self::Block,
+            Ok("pipe") => Self::Pipe,
+            Ok(other) => {
+                log::warn!("Unknown CURSOR_SHAPE \"{other}\", expected \"underscore\", \"block\" or \"pipe\"");
+                Self::Underscore
+            }
+        }
+    }
+
+    /// What is drawn at the cursor. Always a single column wide, which the input boxes reserve for it
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Underscore => "_",
+            Self::Block => "█",
+            Self::Pipe => "|",
+        }
+    }
+}
+
+/// All client settings
+pub struct Config {
+    pub server_url: String,
+    pub cert_pin: Option<String>,
+    /// Sent as "Authorization: Bearer <token>" when connecting, for servers behind an auth proxy
+    pub auth_token: Option<String>,
+    pub welcome_banner: Option<String>,
+    /// Sent right after joining, once per session
+    pub auto_message: Option<String>,
+    pub timestamps: TimestampStyle,
+    /// The room joined right after connecting
+    pub room: String,
+    /// How many times in a row connecting may fail before giving up. Unlimited if None
+    pub max_retries: Option<u32>,
+    /// How long the connection may be idle before TCP keepalive probes are sent
+    pub keepalive_idle: Duration,
+    /// How often TCP keepalive probes are repeated while unanswered
+    pub keepalive_interval: Duration,
+    /// Whether wide terminals show the room list and the roster on either side of the chat
+    pub wide_layout: bool,
+    /// Commands which are only run once confirmed with "/yes", i.e. "/quit"
+    pub confirm_commands: Vec<String>,
+    /// The color everyone else renders this user in, picked again after every join
+    pub color: Option<String>,
+    /// Where inputs recalled with the Up arrow are kept between sessions. Only kept in memory if None
+    pub history_file: Option<PathBuf>,
+    /// How many inputs are kept for recalling
+    pub history_size: usize,
+    /// Consecutive messages from the same sender within this long of each other only show the sender once
+    /// Every message shows it if None
+    pub group_window: Option<Duration>,
+    /// Whether the mouse wheel scrolls the chat. Terminals can only select text natively without it
+    pub mouse_capture: bool,
+    /// The optional terminal modes which may be used
+    pub terminal_features: TerminalFeatures,
+    /// For how long after scrolling new messages don't move the chat, even back at the bottom
+    pub scroll_pause: Duration,
+    /// How many lines the input box grows to as long messages are typed
+    pub input_max_lines: usize,
+    /// Run alongside the bell, i.e. ["paplay", "ding.wav"]
+    pub notify_command: Option<Vec<String>>,
+    pub notify_on: NotifyOn,
+    pub cursor_shape: CursorShape,
+    /// Whether the cursor blinks. It is always shown while it doesn't
+    pub cursor_blink: bool,
+    /// Whether waiting to connect shows an animated spinner, rather than a still notice
+    pub connection_spinner: bool,
+}
+impl Config {
+    /// Reads the configuration from environment variables, overridden by the command-line flags
+    /// Returns an error describing the problem if a setting is invalid
+    pub fn load() -> Result<Self, String> {
+        let mut config = Self::from_env()?;
+        config.apply_args(std::env::args().skip(1))?;
+
+        validate_room_name(&config.room).map_err(|reason| format!("Invalid room \"{}\": {reason}", config.room))?;
+        if let Some(color) = &config.color {
+            config.color = Some(validate_color(color).map_err(|reason| format!("Invalid COLOR \"{color}\": {reason}"))?);
+        }
+        Ok(config)
+    }
+
+    /// Overrides settings with command-line flags:
+    ///   --room <name>
+    ///   --max-retries <count>
+    ///   --invite <link>, as made with "/invite"
+    fn apply_args(&mut self, mut args: impl Iterator<Item = String>) -> Result<(), String> {
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--room" => self.room = args.next().ok_or("Missing value for --room")?,
+                "--max-retries" => {
+                    let value = args.next().ok_or("Missing value for --max-retries")?;
+                    self.max_retries = Some(value.parse().map_err(|_| format!("Invalid value \"{value}\" for --max-retries"))?);
+                },
+                "--invite" => {
+                    let link = args.next().ok_or("Missing value for --invite")?;
+                    let invite = Invite::parse_link(&link).map_err(|reason| format!("Invalid invite link \"{link}\": {reason}"))?;
+                    self.server_url = invite.server;
+                    self.room = invite.room;
+                    if invite.token.is_some() {
+                        self.auth_token = invite.token;
+                    }
+                },
+                _ => return Err(format!("Unknown argument \"{arg}\"")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the configuration from environment variables, falling back to defaults
+    /// Returns an error for the settings which are refused rather than ignored when invalid
+    fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            server_url: std::env::var("SERVER_IP").unwrap_or(DEFAULT_SERVER_URL.to_string()),
+            cert_pin: std::env::var("CERT_PIN").ok(),
+            auth_token: std::env::var("AUTH_TOKEN").ok().filter(|token| !token.is_empty()),
+
+            // Literal "\n" sequences are turned into line breaks
+            welcome_banner: std::env::var("WELCOME_BANNER")
+                .ok()
+                .filter(|banner| !banner.trim().is_empty())
+                .map(|banner| banner.replace("\\n", "\n")),
+
+            auto_message: std::env::var("AUTO_MESSAGE").ok().filter(|message| !message.trim().is_empty()),
+            timestamps: TimestampStyle::from_env(),
+            room: std::env::var("ROOM").unwrap_or(DEFAULT_ROOM.to_string()),
+            max_retries: match std::env::var("MAX_RETRIES") {
+                Ok(value) => Some(value.parse().map_err(|_| format!("Invalid value \"{value}\" for MAX_RETRIES"))?),
+                Err(_) => None,
+            },
+            keepalive_idle: secs_from_env("KEEPALIVE_IDLE_SECS", DEFAULT_KEEPALIVE_IDLE_SECS),
+            keepalive_interval: secs_from_env("KEEPALIVE_INTERVAL_SECS", DEFAULT_KEEPALIVE_INTERVAL_SECS),
+            wide_layout: std::env::var("WIDE_LAYOUT").is_ok_and(|value| value == "true"),
+            mouse_capture: std::env::var("MOUSE_CAPTURE").map_or(true, |value| value != "false"),
+            terminal_features: TerminalFeatures::from_env(),
+            scroll_pause: Duration::from_secs(
+                std::env::var("SCROLL_PAUSE_SECS").ok().and_then(|value| value.parse().ok()).unwrap_or(DEFAULT_SCROLL_PAUSE_SECS)
+            ),
+            input_max_lines: std::env::var("INPUT_MAX_LINES")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_INPUT_MAX_LINES)
+                .max(1),
+
+            // Split on whitespace, as there is no shell to do it
+            notify_command: std::env::var("NOTIFY_COMMAND")
+                .ok()
+                .map(|command| command.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+                .filter(|command| !command.is_empty()),
+            notify_on: NotifyOn::from_env(),
+            cursor_shape: CursorShape::from_env(),
+            cursor_blink: std::env::var("CURSOR_BLINK").is_ok_and(|value| value == "true"),
+            connection_spinner: std::env::var("CONNECTION_SPINNER").map_or(true, |value| value != "false"),
+
+            color: std::env::var("COLOR").ok().filter(|color| !color.trim().is_empty()),
+
+            // An empty HISTORY_FILE keeps the history in memory only
+            history_file: match std::env::var("HISTORY_FILE") {
+                Ok(path) if path.trim().is_empty() => None,
+                Ok(path) => Some(PathBuf::from(path)),
+                Err(_) => default_history_file(),
+            },
+            history_size: std::env::var("HISTORY_SIZE").ok().and_then(|value| value.parse().ok()).unwrap_or(DEFAULT_HISTORY_SIZE),
+            group_window: std::env::var("GROUP_WINDOW_SECS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+
+            // The prefix is optional, so "quit,clear" works just as well
+            confirm_commands: std::env::var("CONFIRM_COMMANDS")
+                .map(|commands| commands
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|command| !command.is_empty())
+                    .map(|command| format!("{COMMAND_PREFIX}{}", command.trim_start_matches(COMMAND_PREFIX)))
+                    .collect())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Reads a duration in seconds from the environment variable "name", falling back to "default" if unset or invalid
+fn secs_from_env(name: &str, default: u64) -> Duration {
+    let secs = std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default);
+    Duration::from_secs(secs.max(1))
+}
+
+/// Returns where the input history is kept by default: under $XDG_CONFIG_HOME, or ~/.config if unset
+fn default_history_file() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").filter(|home| !home.is_empty()).map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join(HISTORY_FILE_NAME))
+}
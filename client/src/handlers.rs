@@ -10,28 +10,202 @@ use crossterm::event::{Event, KeyCode, KeyModifiers, MouseEventKind};
 use futures_util::{
     SinkExt, StreamExt,
 };
-use std::{io::Error, sync::Arc};
-use shared::{ClientMessage, HandleError, WSRead, WSWrite};
-use tokio::sync::{mpsc::{UnboundedReceiver, UnboundedSender}, Mutex};
-use tokio_tungstenite::tungstenite::Message;
+use std::{collections::HashMap, io::Error, sync::Arc, time::Duration};
+use shared::{close_frame, from_json_limited, ClientMessage, HandleError, Hello, MessageKind, WSRead, WSWrite};
+use tokio::sync::{mpsc::{error::TrySendError, Receiver, Sender}, Mutex};
+use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
 
+// Constants
+const UNSTRUCTURED_MARKER: &str = "[unstructured]";     // Prefixes text from the server which isn't a message
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2); // How long the server gets to close the connection, see "wait_for_close"
 
 /// Custom enum for keyboard handling
 pub enum HandlingSignal{
     Continue,
     End,
     Quit,
+    /// Shows or hides the sidebars of the wide layout
+    ToggleSidebars,
+    /// Turns capturing the mouse on or off
+    ToggleMouse,
+    /// Recalls the input before the current one
+    RecallPrevious,
+    /// Recalls the input after the current one
+    RecallNext,
+}
+
+/// What the TUI is told has changed
+pub enum Notification{
+    /// A new message was added to the history
+    Message,
+    /// The roster or the room list changed
+    Directory,
+    /// The history was cleared
+    Cleared,
+    /// Main is connected again after "/reconnect"
+    Connected,
+}
+
+/// What the TUI hands over to main
+pub enum Outgoing{
+    /// A message or a command to relay to the server
+    Text(String),
+    /// Introduces the client to the server, as the first message of the connection
+    Hello(Hello),
+    /// Asks for the connection to be set up again, introducing the client with the given hello
+    Reconnect(Hello),
+}
+
+/// Who is in the current room and which rooms exist, as last told by the server
+#[derive(Default)]
+pub struct Directory{
+    pub roster: Vec<String>,
+    pub rooms: Vec<String>,
+    /// The colors users picked, by username
+    pub colors: HashMap<String, String>,
+    /// How many messages were posted in each room, by room name, for the unread counts
+    pub activity: HashMap<String, u64>,
+}
+
+/// The text being typed by the user, along with the cursor position within it
+#[derive(Default)]
+pub struct InputBuffer{
+    chars: Vec<char>,
+    cursor: usize,
+}
+impl InputBuffer{
+    /// Inserts a char at the cursor position and moves the cursor past it
+    pub fn insert(&mut self, char: char){
+        self.chars.insert(self.cursor, char);
+        self.cursor += 1;
+    }
+
+    /// Removes the char right before the cursor
+    pub fn backspace(&mut self){
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// Removes the char under the cursor
+    pub fn delete(&mut self){
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    /// Moves the cursor one char to the left
+    pub fn move_left(&mut self){
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    /// Moves the cursor one char to the right
+    pub fn move_right(&mut self){
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    /// Moves the cursor to the start of the buffer
+    pub fn move_home(&mut self){
+        self.cursor = 0;
+    }
+
+    /// Moves the cursor to the end of the buffer
+    pub fn move_end(&mut self){
+        self.cursor = self.chars.len();
+    }
+
+    /// Replaces the contents of the buffer with "text", moving the cursor to its end
+    pub fn set(&mut self, text: &str){
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    /// Removes every control char, such as newlines, or swaps it for "replacement" if there is one
+    /// The cursor stays next to the same chars. Returns how many control chars there were
+    pub fn remove_control(&mut self, replacement: Option<char>) -> usize{
+        let before_cursor = self.chars[..self.cursor].iter().filter(|char| char.is_control()).count();
+        let count = self.chars.iter().filter(|char| char.is_control()).count();
+        match replacement {
+            Some(replacement) => self.chars.iter_mut().filter(|char| char.is_control()).for_each(|char| *char = replacement),
+            None => {
+                self.chars.retain(|char| !char.is_control());
+                self.cursor -= before_cursor;
+            }
+        }
+        count
+    }
+
+    /// Like "remove_control", but keeps line breaks and tabs, turning "\r\n" and lone "\r" into "\n"
+    /// For the chat input, where pasted lines stay lines of the same message
+    pub fn remove_control_but_lines(&mut self){
+        let mut chars = Vec::with_capacity(self.chars.len());
+        let mut cursor = 0;
+        for (index, char) in self.chars.iter().enumerate() {
+            if index == self.cursor {
+                cursor = chars.len();
+            }
+            match char {
+                '\r' if self.chars.get(index + 1) == Some(&'\n') => {},
+                '\r' => chars.push('\n'),
+                '\n' | '\t' => chars.push(*char),
+                char if char.is_control() => {},
+                char => chars.push(*char),
+            }
+        }
+        if self.cursor == self.chars.len() {
+            cursor = chars.len();
+        }
+        self.chars = chars;
+        self.cursor = cursor;
+    }
+
+    /// Empties the buffer
+    pub fn clear(&mut self){
+        self.chars.clear();
+        self.cursor = 0;
+    }
+
+    /// A getter method for the chars
+    pub fn get_chars(&self) -> &[char]{
+        &self.chars
+    }
+
+    /// A getter method for the cursor position
+    pub fn get_cursor(&self) -> usize{
+        self.cursor
+    }
+
+    /// Returns the buffer contents as a String
+    pub fn to_text(&self) -> String{
+        self.chars.iter().collect()
+    }
+}
+
+/// Sends "hello" to the server, as the first message of a connection
+pub async fn introduce(hello: &Hello, stream_write: &mut WSWrite) {
+    match serde_json::to_string(hello) {
+        Ok(ser_hello) => {
+            if let Err(err) = stream_write.send(Message::text(ser_hello)).await {
+                log::error!("Could not send hello to server: {err}");
+            }
+        }
+        Err(err) => log::error!("Could not serialize hello: {err}"),
+    }
 }
 
 /// Awaits a message from receiver and attempts to relay it to the server
+/// Returns the hello to reconnect with, if the user asked for it
 /// If the received message is None, returns a "HandleError::ConnectionDropped" error
 pub async fn handle_user_input(
-    receiver: &mut UnboundedReceiver<String>,
+    receiver: &mut Receiver<Outgoing>,
     stream_write: &mut WSWrite,
-) -> Result<(), HandleError> {
+) -> Result<Option<Hello>, HandleError> {
     // Wait for an input message from the TUI
     match receiver.recv().await {
-        Some(input_string) => {
+        Some(Outgoing::Hello(hello)) => introduce(&hello, stream_write).await,
+        Some(Outgoing::Reconnect(hello)) => return Ok(Some(hello)),
+        Some(Outgoing::Text(input_string)) => {
             let input_as_msg = Message::from(input_string);
 
             // Send message to server
@@ -44,36 +218,77 @@ pub async fn handle_user_input(
             return Err(HandleError::ConnectionDropped);
         }
     }
-    Ok(())
+    Ok(None)
+}
+
+/// Tells the TUI task what changed
+/// A full channel means a redraw is coming anyway, so messages and directory changes are coalesced into it by
+/// being dropped. The TUI tells how many messages are new from the history itself, so nothing is lost with them.
+/// Clearing and reconnecting always get through, waiting for room if needed
+pub async fn notify(notifier_tx: &Sender<Notification>, notification: Notification) {
+    let result = match notification {
+        Notification::Cleared | Notification::Connected => notifier_tx.send(notification).await.map_err(|_| "channel closed"),
+        _ => match notifier_tx.try_send(notification) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Closed(_)) => Err("channel closed"),
+        },
+    };
+    if let Err(notifier_error) = result {
+        log::error!("Could not notify TUI task of new message from server: {notifier_error}");
+    }
+}
+
+/// Tells the server that the client is leaving on purpose, rather than having crashed
+pub async fn close_connection(stream_write: &mut WSWrite) {
+    let frame = close_frame(CloseCode::Normal, "client quit");
+    if let Err(close_error) = stream_write.send(Message::Close(Some(frame))).await {
+        log::warn!("Could not close the connection cleanly: {close_error}");
+    }
+}
+
+/// Waits for the server to end the connection after "close_connection", so that it has let go of the
+/// username before it is introduced again. Gives up after CLOSE_TIMEOUT
+pub async fn wait_for_close(stream_read: &mut WSRead) {
+    let drained = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while let Some(Ok(_)) = stream_read.next().await {}
+    });
+    if drained.await.is_err() {
+        log::warn!("The server did not close the connection within {} s", CLOSE_TIMEOUT.as_secs());
+    }
 }
 
 /// Awaits for and deals with a message received from the server via "stream_read" and appends it as
-/// a ClientMessage in "history"
-/// Notifies the TUI for this new message, if valid
+/// a ClientMessage in "history", or updates "directory" if it is a roster or room list
+/// Notifies the TUI for this change, if valid
 /// If the received message is None, returns a "HandleError::ConnectionDropped" error
+/// If the server closes the connection with a reason, returns it as a "HandleError::Disconnected" error when the client
+/// was turned away, i.e. kicked, or as a "HandleError::Interrupted" one otherwise, i.e. when the server shuts down
 pub async fn handle_server_message(
     stream_read: &mut WSRead,
     history: Arc<Mutex<Vec<ClientMessage>>>,
-    notifier_tx: UnboundedSender<()>,
+    directory: Arc<Mutex<Directory>>,
+    notifier_tx: Sender<Notification>,
 ) -> Result<(), HandleError> {
     match stream_read.next().await {
         Some(msg_result) => match msg_result {
-            Ok(msg) => match serde_json::from_str(msg.to_string().as_str()) {
-                Ok(rec_msg) => {
-                    // Append to history
-                    history.lock().await.push(rec_msg);
-
-                    // Notify the TUI task of changes
-                    if let Err(notifier_error) = notifier_tx.send(()) {
-                        log::error!("Could not notify TUI task of new message from server: {notifier_error}");
-                    }
-
-                    log::info!("Received from server: {msg:?}");
-                }
-                Err(err) => {
-                    log::error!("Could not deserialize message from server: {err}");
-                }
-            },
+            Ok(Message::Close(Some(frame))) if frame.code == CloseCode::Again => {
+                log::info!("Server is full: {}", frame.reason);
+                return Err(HandleError::ServerFull);
+            }
+            Ok(Message::Close(Some(frame))) if frame.code == CloseCode::Policy && !frame.reason.is_empty() => {
+                log::info!("Server closed the connection: {} ({})", frame.reason, frame.code);
+                return Err(HandleError::Disconnected(frame.reason.to_string()));
+            }
+            Ok(Message::Close(Some(frame))) if !frame.reason.is_empty() => {
+                log::info!("Server closed the connection for now: {} ({})", frame.reason, frame.code);
+                return Err(HandleError::Interrupted(frame.reason.to_string()));
+            }
+            // Fragmented messages are put back together by tungstenite, so only whole ones ever get here
+            // Pings are answered by tungstenite as well
+            Ok(Message::Ping(_) | Message::Pong(_)) => {},
+            Ok(Message::Close(_)) => log::info!("Server closed the connection"),
+            Ok(Message::Binary(_) | Message::Frame(_)) => log::warn!("Ignoring a non-text message from the server"),
+            Ok(Message::Text(msg)) => handle_server_text(msg.as_str(), &history, &directory, &notifier_tx).await,
             Err(_) => log::error!("Received message from server is an error"),
         },
         None => return Err(HandleError::ConnectionDropped),
@@ -82,9 +297,87 @@ pub async fn handle_server_message(
     Ok(())
 }
 
+/// Handles a text frame from the server, see "handle_server_message"
+/// Anything which doesn't even look like JSON is shown as unstructured text. Payloads which do but were refused,
+/// i.e. for being nested too deep, are only logged
+async fn handle_server_text(
+    msg: &str,
+    history: &Mutex<Vec<ClientMessage>>,
+    directory: &Mutex<Directory>,
+    notifier_tx: &Sender<Notification>,
+) {
+    match from_json_limited::<ClientMessage>(msg) {
+        // Left over from a room the client has left since, should the server not have dropped it already
+        Ok(rec_msg) if rec_msg.get_kind().is_room_bound() && left_room(&rec_msg, &history.lock().await) => {
+            log::debug!("Dropping a message for #{} received after leaving it", rec_msg.get_room().unwrap_or_default());
+        }
+        Ok(rec_msg) => {
+            // Directory updates are shown aside, rather than as messages
+            let notification = match rec_msg.get_kind() {
+                MessageKind::Roster | MessageKind::RoomList | MessageKind::Colors | MessageKind::RoomActivity => {
+                    let mut directory = directory.lock().await;
+                    let body = rec_msg.get_message();
+                    let parsed = match rec_msg.get_kind() {
+                        MessageKind::Roster => from_json_limited(&body).map(|roster| directory.roster = roster),
+                        MessageKind::RoomList => from_json_limited(&body).map(|rooms| directory.rooms = rooms),
+                        MessageKind::RoomActivity => from_json_limited(&body).map(|activity| directory.activity = activity),
+                        _ => from_json_limited(&body).map(|colors| directory.colors = colors),
+                    };
+                    if let Err(err) = parsed {
+                        log::error!("Could not deserialize directory from server: {err}");
+                    }
+                    Notification::Directory
+                }
+                MessageKind::ClearHistory => {
+                    clear_history(&mut *history.lock().await);
+                    Notification::Cleared
+                }
+                _ => {
+                    history.lock().await.push(rec_msg);
+                    Notification::Message
+                }
+            };
+
+            // Notify the TUI task of changes
+            notify(notifier_tx, notification).await;
+
+            log::info!("Received from server: {msg:?}");
+        }
+        // Plain text, i.e. from a simpler server, is still shown rather than lost
+        Err(_) if !msg.trim_start().starts_with(['{', '[']) => {
+            log::warn!("Received unstructured text from server: {msg:?}");
+            history.lock().await.push(ClientMessage::system(format!("{UNSTRUCTURED_MARKER} {msg}")));
+            notify(notifier_tx, Notification::Message).await;
+        }
+        Err(err) => {
+            log::error!("Could not deserialize message from server: {err}");
+        }
+    }
+}
+
+/// Whether "message" was delivered into a room other than the one "history" says the client is in now
+/// Messages which don't say their room, or arrive before the client joined any, are never considered left
+fn left_room(message: &ClientMessage, history: &[ClientMessage]) -> bool {
+    let current_room = history
+        .iter()
+        .rev()
+        .find(|client_message| client_message.get_kind() == MessageKind::Joined)
+        .map(ClientMessage::get_message);
+    matches!((message.get_room(), current_room), (Some(room), Some(current_room)) if room != current_room)
+}
+
+/// Empties "history", except for what says who and where the client is
+pub fn clear_history(history: &mut Vec<ClientMessage>) {
+    history.retain(|client_message| matches!(
+        client_message.get_kind(),
+        MessageKind::Rename | MessageKind::Joined | MessageKind::RoomAccess
+    ));
+}
+
 /// Handles a single keyboard event and returns a signal
-/// Will write char to buffer, as well as pop from it in case of Backspace input
-pub fn handle_input_event(keyboard_event: Option<Result<Event, Error>>, buffer: &mut Vec<char>, scroll: &mut i8) -> HandlingSignal {
+/// Will write char to buffer at the cursor, as well as remove from it in case of Backspace/Delete input
+/// Focus changes are recorded in "focused", for terminals which report them
+pub fn handle_input_event(keyboard_event: Option<Result<Event, Error>>, buffer: &mut InputBuffer, scroll: &mut i8, focused: &mut bool) -> HandlingSignal {
     match keyboard_event{
         Some(Ok(event)) => match event {
             Event::Key(key) => match key.code{
@@ -93,11 +386,26 @@ pub fn handle_input_event(keyboard_event: Option<Result<Event, Error>>, buffer:
                     if char == 'c' && key.modifiers == KeyModifiers::CONTROL {
                         return HandlingSignal::Quit
                     }
+                    if char == 'r' && key.modifiers == KeyModifiers::CONTROL {
+                        return HandlingSignal::ToggleSidebars
+                    }
+                    if char == 't' && key.modifiers == KeyModifiers::CONTROL {
+                        return HandlingSignal::ToggleMouse
+                    }
 
                     // Update input box
-                    buffer.push(char);
+                    buffer.insert(char);
                 }
-                KeyCode::Backspace => _ = buffer.pop(),
+                KeyCode::Backspace => buffer.backspace(),
+                KeyCode::Delete => buffer.delete(),
+                KeyCode::Left => buffer.move_left(),
+                KeyCode::Right => buffer.move_right(),
+                KeyCode::Home => buffer.move_home(),
+                KeyCode::End => buffer.move_end(),
+                KeyCode::Up => return HandlingSignal::RecallPrevious,
+                KeyCode::Down => return HandlingSignal::RecallNext,
+                KeyCode::PageUp => *scroll = 1,
+                KeyCode::PageDown => *scroll = -1,
                 KeyCode::Enter => {
                     return HandlingSignal::End;
                 },
@@ -108,6 +416,10 @@ pub fn handle_input_event(keyboard_event: Option<Result<Event, Error>>, buffer:
                 MouseEventKind::ScrollUp => *scroll = 1,
                 _ => return HandlingSignal::Continue,
             }
+            // Pasted text comes in one piece, newlines included, rather than as keys which could submit it early
+            Event::Paste(text) => text.chars().for_each(|char| buffer.insert(char)),
+            Event::FocusGained => *focused = true,
+            Event::FocusLost => *focused = false,
             _ => return HandlingSignal::Continue,
         },
         Some(Err(_)) => return HandlingSignal::Quit,
@@ -116,3 +428,88 @@ pub fn handle_input_event(keyboard_event: Option<Result<Event, Error>>, buffer:
 
     HandlingSignal::Continue
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn receive(text: &str) -> Vec<ClientMessage> {
+        let history = Mutex::new(Vec::new());
+        let directory = Mutex::new(Directory::default());
+        let (notifier_tx, _notifier_rx) = tokio::sync::mpsc::channel(4);
+        handle_server_text(text, &history, &directory, &notifier_tx).await;
+        history.into_inner()
+    }
+
+    #[tokio::test]
+    async fn bare_text_frame_is_shown() {
+        let history = receive("plain hello from a bare server").await;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].get_kind(), MessageKind::System);
+        assert_eq!(history[0].get_message(), format!("{UNSTRUCTURED_MARKER} plain hello from a bare server"));
+    }
+
+    #[tokio::test]
+    async fn structured_message_is_shown_as_is() {
+        let history = receive(r#"{"input_message":"hi","from_username":"bob","timestamp":0}"#).await;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].get_username(), "bob");
+        assert_eq!(history[0].get_message(), "hi");
+    }
+
+    #[tokio::test]
+    async fn refused_json_is_dropped() {
+        let nested = "[".repeat(100_000);
+        assert!(receive(&nested).await.is_empty());
+        assert!(receive(r#"  {"input_message": 3}"#).await.is_empty());
+    }
+
+    /// Sends "message" from a fake server
+    async fn send_from_server(ws: &mut tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>, message: ClientMessage) {
+        ws.send(Message::text(serde_json::to_string(&message).unwrap())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_sent_right_after_the_hello_are_kept() {
+        // A server which greets the client as soon as it accepts its hello, then confirms the join
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut ws = tokio_tungstenite::accept_async(listener.accept().await.unwrap().0).await.unwrap();
+            let hello: Hello = from_json_limited(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+            send_from_server(&mut ws, ClientMessage::new("alice".to_string(), format!("welcome, {}", hello.username))).await;
+            send_from_server(&mut ws, ClientMessage::builder().username(shared::SYSTEM_USERNAME.to_string()).body("lobby".to_string()).kind(MessageKind::Joined).build().unwrap()).await;
+            while ws.next().await.is_some() {}
+        });
+
+        // Main, relaying the hello from the prompt and reading from the server meanwhile
+        let (mut write, mut read) = tokio_tungstenite::connect_async(url).await.unwrap().0.split();
+        let history = Arc::new(Mutex::new(Vec::new()));
+        let (notifier_tx, mut notifier_rx) = tokio::sync::mpsc::channel(4);
+        let (input_tx, mut input_rx) = tokio::sync::mpsc::channel(4);
+        let main_history = Arc::clone(&history);
+        tokio::spawn(async move {
+            let directory = Arc::new(Mutex::new(Directory::default()));
+            loop {
+                tokio::select! {
+                    result = handle_user_input(&mut input_rx, &mut write) => if result.is_err() {break},
+                    result = handle_server_message(&mut read, Arc::clone(&main_history), Arc::clone(&directory), notifier_tx.clone()) => if result.is_err() {break},
+                }
+            }
+        });
+
+        // The prompt, which leaves for the chat loop as soon as the join is confirmed
+        input_tx.send(Outgoing::Hello(Hello{ username: "bob".to_string(), room: None })).await.unwrap();
+        let joined = tokio::time::timeout(Duration::from_secs(5), async {
+            while notifier_rx.recv().await.is_some() {
+                if history.lock().await.iter().any(|client_message| client_message.get_kind() == MessageKind::Joined) {
+                    return;
+                }
+            }
+        });
+        joined.await.unwrap();
+
+        let history = history.lock().await;
+        assert!(history.iter().any(|client_message| client_message.get_message() == "welcome, bob"));
+    }
+}
@@ -9,21 +9,41 @@
 //********************************************************************
 
 
-use std::{sync::Arc, time::Duration};
+use std::{sync::Arc, time::{Duration, Instant}};
 
-use crossterm::{event::{DisableMouseCapture, EnableMouseCapture}, execute, terminal::{disable_raw_mode, enable_raw_mode}};
+use crossterm::{cursor::MoveToColumn, execute, style::Print, terminal::{enable_raw_mode, Clear, ClearType}};
 use futures_util::StreamExt;
-use shared::ClientMessage;
+use shared::{enable_keepalive, websocket_config, ClientMessage, HandleError, WSRead, WSWrite};
 use tokio::{
     select,
-    sync::{mpsc::unbounded_channel, Mutex},
-    time::sleep,
+    sync::{mpsc::{channel, Sender}, Mutex},
+    task::JoinError,
+    time::{interval, sleep},
 };
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{client::IntoClientRequest, handshake::client::Request, http::{header::AUTHORIZATION, HeaderValue, StatusCode}, Error},
+    Connector, MaybeTlsStream,
+};
+
+use crate::{config::Config, handlers::{Directory, Notification}};
 
+mod commands;
+mod config;
 mod handlers;
+mod recall;
+mod terminal;
+mod tls;
+mod transcript;
 mod tui;
 
+// Constants
+const RETRY_DELAY: Duration = Duration::from_secs(5);              // Doubled after each failed attempt...
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);         // ...up to this
+const SERVER_FULL_RETRY_DELAY: Duration = Duration::from_secs(30);   // Don't keep knocking on a full server
+const NOTIFIER_CAPACITY: usize = 64;    // Pending redraws for the TUI. Any more are dropped, see "handlers::notify"
+const INPUT_CAPACITY: usize = 32;       // Inputs waiting to be sent. The TUI keeps any more in the input box, see "run_chat"
+
 #[tokio::main]
 async fn main() {
     // Set default logging level
@@ -31,67 +51,173 @@ async fn main() {
         std::env::set_var("RUST_LOG", "info")
     }
 
-    // Set server IP and port
-    let url = match std::env::var("SERVER_IP") {
-        Ok(value) => value,
-        Err(_) => "ws://127.0.0.1:5050".to_string(),
-    };
-
     // Init logger
     simple_logging::log_to_file("chatey_client.log", log::LevelFilter::Debug)
         .expect("Unable to set log to file");
 
-    // Bind the mouse scroll wheel
-    execute!(std::io::stdout(), EnableMouseCapture).expect("Could not bind scrol wheel");
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(config_error) => {
+            eprintln!("{config_error}");
+            std::process::exit(1);
+        }
+    };
+    let url = config.server_url.clone();
+
+    // Optionally pin the server certificate (wss:// only)
+    let connector = match &config.cert_pin {
+        Some(_) if !url.starts_with("wss://") => {
+            eprintln!("Invalid CERT_PIN: {url} is not a wss:// address, so there is no certificate to pin");
+            std::process::exit(1);
+        },
+        Some(pin) => match tls::pinned_connector(pin) {
+            Ok(connector) => Some(connector),
+            Err(pin_error) => {
+                eprintln!("Invalid CERT_PIN: {pin_error}");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let mut exit_message = None;
+
+    // Optional welcome banner, shown once per session as the first SYSTEM message
+    let mut welcome_banner = config.welcome_banner.clone();
+    let mut auto_message = config.auto_message.clone();
+
+    // Ask the terminal to report focus changes and pastes. Not supported everywhere, in which case
+    // the chat is always considered focused and pastes come in as typed keys
+    config.terminal_features.enable();
+    let config = Arc::new(config);
 
     enable_raw_mode().expect("Could not enable terminal raw mode");
 
     // Connection loop
     'outer: loop{
-        // Attempt to connect to server
-        let ws_stream = loop {
-            if let Ok((ws_stream, _)) = connect_async(&url).await {
-                break ws_stream;
+        // Attempt to connect to server, waiting longer after each failure
+        let mut attempt = 0u32;
+        let (mut ws_stream_write, mut ws_stream_read) = loop {
+            match dial(&url, &config, connector.clone()).await {
+                Ok(halves) => break halves,
+                Err(DialError::Fatal(reason)) => {
+                    exit_message = Some(reason);
+                    break 'outer;
+                }
+                Err(DialError::Retry) => {},
+            }
+
+            attempt += 1;
+            if config.max_retries.is_some_and(|max_retries| attempt > max_retries) {
+                exit_message = Some(format!("Failed to connect to {url}, giving up after {attempt} attempt(s)"));
+                break 'outer;
             }
-            println!("Failed to connect to server. Retrying in 5 s");
-            sleep(Duration::from_secs(5)).await;
-        };
 
-        // Split the stream so it can be actually useful
-        let (mut ws_stream_write, mut ws_stream_read) = ws_stream.split();
+            let delay = retry_delay(attempt);
+            log::info!("Connection attempt {attempt} failed. Retrying in {} s", delay.as_secs());
+            wait_to_retry("Failed to connect to server", delay, config.connection_spinner).await;
+        };
 
         // Utilities
         let history: Arc<Mutex<Vec<ClientMessage>>> = Arc::new(Mutex::new(Vec::new()));
-        let (notifier_tx, notifier_rx) = unbounded_channel();
-        let (input_tx, mut input_rx) = unbounded_channel();
+        if let Some(banner) = welcome_banner.take() {
+            history.lock().await.push(ClientMessage::system(banner));
+        }
+        let directory = Arc::new(Mutex::new(Directory::default()));
+        let (notifier_tx, notifier_rx) = channel(NOTIFIER_CAPACITY);
+        let (input_tx, mut input_rx) = channel(INPUT_CAPACITY);
 
         // Init the TUI
         let history_clone = Arc::clone(&history);
-        let tui_handler = tokio::spawn(async {
+        let directory_clone = Arc::clone(&directory);
+        let config_clone = Arc::clone(&config);
+        let auto_message = auto_message.take();
+        let mut tui_handler = tokio::spawn(async {
             let terminal = ratatui::init();
             if let Err(run_error) =
-                tui::run_chat(terminal, history_clone, notifier_rx, input_tx).await
+                tui::run_chat(terminal, history_clone, directory_clone, notifier_rx, input_tx, config_clone, auto_message).await
             {
                 log::error!("Error while running TUI: {run_error}");
             };
-            ratatui::restore();
+            tui::restore_terminal();
         });
 
-        // Handle messages to and from the server
+        // Handle messages to and from the server, as long as the TUI is up
+        // This starts with the username prompt, so that whatever the server sends before or right after letting the
+        // client in is already in the history once the chat shows up, rather than waiting in the socket
         loop {
             select! {
+                tui_result = &mut tui_handler => {
+                    if let Some(reason) = crash_reason(tui_result) {
+                        exit_message = Some(format!("The chat crashed: {reason}"));
+                    }
+                    handlers::close_connection(&mut ws_stream_write).await;
+                    break 'outer;
+                },
                 handle_result = handlers::handle_user_input(&mut input_rx, &mut ws_stream_write) => match handle_result{
-                    Ok(_) => log::debug!("Message captured from user"),
+                    Ok(None) => log::debug!("Message captured from user"),
+                    Ok(Some(hello)) => {
+                        // Swap the connection under the same TUI and history, once the server has let go of the username
+                        log::info!("Reconnecting to {url} as asked by the user");
+                        handlers::close_connection(&mut ws_stream_write).await;
+                        handlers::wait_for_close(&mut ws_stream_read).await;
+                        drop((ws_stream_write, ws_stream_read));
+                        select! {
+                            tui_result = &mut tui_handler => {
+                                if let Some(reason) = crash_reason(tui_result) {
+                                    exit_message = Some(format!("The chat crashed: {reason}"));
+                                }
+                                break 'outer;
+                            },
+                            redial_result = redial(&url, &config, connector.clone(), &history, &notifier_tx) => match redial_result {
+                                Ok(halves) => (ws_stream_write, ws_stream_read) = halves,
+                                Err(reason) => {
+                                    tui_handler.abort();
+                                    _ = tui_handler.await;
+                                    exit_message = Some(reason);
+                                    break 'outer;
+                                },
+                            },
+                        }
+                        handlers::introduce(&hello, &mut ws_stream_write).await;
+                    },
                     Err(_) => {
-                        tui_handler.abort();
+                        // The TUI is gone, meaning the user quit, unless it crashed
+                        if let Some(reason) = crash_reason(tui_handler.await) {
+                            exit_message = Some(format!("The chat crashed: {reason}"));
+                        }
+                        handlers::close_connection(&mut ws_stream_write).await;
                         break 'outer;
                     },
                 },
-                handle_result = handlers::handle_server_message(&mut ws_stream_read, Arc::clone(&history), notifier_tx.clone()) => match handle_result{
+                handle_result = handlers::handle_server_message(&mut ws_stream_read, Arc::clone(&history), Arc::clone(&directory), notifier_tx.clone()) => match handle_result{
                     Ok(_) => log::debug!("Message received from server"),
+                    Err(HandleError::Disconnected(reason)) => {
+                        // Wait for the TUI to be gone, so it can't draw over the reason
+                        tui_handler.abort();
+                        _ = tui_handler.await;
+                        exit_message = Some(format!("Disconnected: {reason}"));
+                        break 'outer;
+                    },
+                    Err(HandleError::ServerFull) => {
+                        // Wait for the TUI to be gone, so it can't draw over the notice
+                        tui_handler.abort();
+                        _ = tui_handler.await;
+                        tui::restore_terminal();
+                        wait_to_retry("The server is full", SERVER_FULL_RETRY_DELAY, config.connection_spinner).await;
+                        continue 'outer;
+                    },
+                    Err(HandleError::Interrupted(reason)) => {
+                        // Likewise, so the reason stays on screen while connecting again
+                        tui_handler.abort();
+                        _ = tui_handler.await;
+                        tui::restore_terminal();
+                        println!("Disconnected: {reason}. Reconnecting");
+                        continue 'outer;
+                    },
                     Err(_) => {
                         tui_handler.abort();
-                        ratatui::restore();
+                        _ = tui_handler.await;
+                        tui::restore_terminal();
                         continue 'outer;
                     },
                 }
@@ -100,7 +226,143 @@ async fn main() {
     }
 
     // Cleanup
-    ratatui::restore();
-    execute!(std::io::stdout(), DisableMouseCapture).expect("Could not unbind scrol wheel");
-    disable_raw_mode().expect("Could not disable raw mode");
+    tui::restore_terminal();
+    config.terminal_features.disable();
+
+    if let Some(message) = exit_message {
+        eprintln!("{message}");
+        std::process::exit(1);
+    }
+}
+
+/// Returns why the TUI task ended, if it panicked, logging it
+fn crash_reason(tui_result: Result<(), JoinError>) -> Option<String> {
+    let payload = match tui_result {
+        Err(join_error) if join_error.is_panic() => join_error.into_panic(),
+        _ => return None,
+    };
+
+    let reason = payload
+        .downcast_ref::<&str>()
+        .map(|reason| reason.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or("unknown reason".to_string());
+    log::error!("The TUI crashed: {reason}");
+    Some(reason)
+}
+
+/// Why connecting to the server failed
+enum DialError{
+    /// Worth trying again later
+    Retry,
+    /// Bound to fail again, for the reason given
+    Fatal(String),
+}
+
+/// Attempts to connect to the server at "url" once, returning both halves of the connection
+/// Has the kernel notice a dead route even while the chat is idle, through TCP keepalive
+async fn dial(url: &str, config: &Config, connector: Option<Connector>) -> Result<(WSWrite, WSRead), DialError> {
+    let request = match handshake_request(url, config.auth_token.as_deref()) {
+        Ok(request) => request,
+        Err(request_error) => {
+            log::error!("Could not build the handshake request for {url}: {request_error}");
+            return Err(DialError::Fatal(format!("Invalid server address {url}: {request_error}")));
+        }
+    };
+    let ws_stream = match connect_async_tls_with_config(request, Some(websocket_config()), false, connector).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(Error::Http(response)) if response.status() == StatusCode::UNAUTHORIZED => {
+            log::error!("The server at {url} refused the token: {response:?}");
+            return Err(DialError::Fatal(format!("The server at {url} refused the connection: missing or invalid AUTH_TOKEN")));
+        }
+        Err(connect_error) if tls::is_pin_mismatch(&connect_error) => {
+            log::error!("Refusing to connect to {url}: {connect_error}");
+            return Err(DialError::Fatal(format!("Refusing to connect to {url}: the server certificate does not match CERT_PIN")));
+        }
+        Err(connect_error) => {
+            log::warn!("Could not connect to {url}: {connect_error}");
+            return Err(DialError::Retry);
+        }
+    };
+
+    let tcp_stream = match ws_stream.get_ref() {
+        MaybeTlsStream::Plain(stream) => Some(stream),
+        MaybeTlsStream::Rustls(stream) => Some(stream.get_ref().0),
+        _ => None,
+    };
+    if let Some(Err(keepalive_error)) = tcp_stream.map(|stream| enable_keepalive(stream, config.keepalive_idle, config.keepalive_interval)) {
+        log::warn!("Could not enable TCP keepalive: {keepalive_error}");
+    }
+    Ok(ws_stream.split())
+}
+
+/// Connects to the server at "url" again for "/reconnect", retrying like the first connection
+/// The TUI owns the terminal meanwhile, so the user is kept posted through "history" instead
+/// Returns why it gave up, if it did
+async fn redial(
+    url: &str,
+    config: &Config,
+    connector: Option<Connector>,
+    history: &Mutex<Vec<ClientMessage>>,
+    notifier_tx: &Sender<Notification>,
+) -> Result<(WSWrite, WSRead), String> {
+    let mut attempt = 0u32;
+    loop {
+        let delay = match dial(url, config, connector.clone()).await {
+            Ok(halves) => {
+                history.lock().await.push(ClientMessage::system(format!("Reconnected to {url}")));
+                handlers::notify(notifier_tx, Notification::Message).await;
+                handlers::notify(notifier_tx, Notification::Connected).await;
+                return Ok(halves);
+            }
+            Err(DialError::Fatal(reason)) => return Err(reason),
+            Err(DialError::Retry) => {
+                attempt += 1;
+                if config.max_retries.is_some_and(|max_retries| attempt > max_retries) {
+                    return Err(format!("Failed to reconnect to {url}, giving up after {attempt} attempt(s)"));
+                }
+                retry_delay(attempt)
+            }
+        };
+
+        log::info!("Reconnection attempt {attempt} failed. Retrying in {} s", delay.as_secs());
+        history.lock().await.push(ClientMessage::system(format!("Could not reconnect. Retrying in {} s", delay.as_secs())));
+        handlers::notify(notifier_tx, Notification::Message).await;
+        sleep(delay).await;
+    }
+}
+
+/// Waits "delay" before connecting again, posting "reason" on the terminal, which no TUI owns meanwhile
+/// With "spinner", the notice is animated and counts down, and is wiped once the wait is over
+async fn wait_to_retry(reason: &str, delay: Duration, spinner: bool) {
+    if !spinner {
+        println!("{reason}. Retrying in {} s", delay.as_secs());
+        sleep(delay).await;
+        return;
+    }
+
+    let started = Instant::now();
+    let mut ticker = interval(tui::SPINNER_INTERVAL);
+    while started.elapsed() < delay {
+        let left = (delay - started.elapsed()).as_secs_f64().ceil();
+        let notice = format!("{} {reason}. Retrying in {left} s", tui::spinner_frame(started.elapsed()));
+        _ = execute!(std::io::stdout(), MoveToColumn(0), Clear(ClearType::CurrentLine), Print(notice));
+        ticker.tick().await;
+    }
+    _ = execute!(std::io::stdout(), MoveToColumn(0), Clear(ClearType::CurrentLine));
+}
+
+/// How long to wait after the failed connection attempt number "attempt", starting at 1
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempt - 1)).min(MAX_RETRY_DELAY)
+}
+
+/// Builds the websocket handshake request for "url", authorized with "token" if given
+fn handshake_request(url: &str, token: Option<&str>) -> Result<Request, String> {
+    let mut request = url.into_client_request().map_err(|err| err.to_string())?;
+    if let Some(token) = token {
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).map_err(|_| "AUTH_TOKEN may only contain visible ASCII characters".to_string())?;
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    Ok(request)
 }
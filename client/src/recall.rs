@@ -0,0 +1,123 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Recalling previous inputs with the Up and Down arrows, kept in  #
+//   a file between sessions                                         #
+//********************************************************************
+
+use std::{fs::{self, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}};
+
+/// Everything the user typed in, oldest first, along with where Up/Down are in it
+pub struct InputHistory{
+    entries: Vec<String>,
+    /// Where entries are kept between sessions. Only kept in memory if None
+    path: Option<PathBuf>,
+    /// How many entries are kept
+    capacity: usize,
+    /// The entry currently recalled. None while typing a new input
+    position: Option<usize>,
+    /// What was typed before recalling anything, given back once Down goes past the newest entry
+    draft: String,
+}
+impl InputHistory{
+    /// Loads the history kept at "path", if any
+    /// A missing file means an empty history. An unreadable one is logged and ignored
+    pub fn load(path: Option<PathBuf>, capacity: usize) -> Self{
+        let mut entries: Vec<String> = match &path {
+            Some(path) => match fs::read(path) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes)
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                Err(read_error) if read_error.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(read_error) => {
+                    log::warn!("Could not read the input history at {}: {read_error}", path.display());
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+
+        let excess = entries.len().saturating_sub(capacity);
+        entries.drain(..excess);
+
+        Self { entries, path, capacity, position: None, draft: String::new() }
+    }
+
+    /// Adds "entry" as the newest one and saves it, unless it is blank or repeats the newest one
+    /// Also stops recalling, so the next Up starts from the newest entry again
+    pub fn push(&mut self, entry: &str){
+        self.position = None;
+        self.draft.clear();
+
+        // Line breaks would split the entry in two once saved
+        if self.capacity == 0 || entry.trim().is_empty() || entry.contains('\n') || self.entries.last().is_some_and(|last| last == entry) {
+            return;
+        }
+
+        self.entries.push(entry.to_string());
+        let overflowed = self.entries.len() > self.capacity;
+        if overflowed {
+            self.entries.remove(0);
+        }
+
+        if let Some(path) = &self.path {
+            // Appending is enough until the file has to shrink
+            let result = if overflowed {Self::rewrite(path, &self.entries)} else {Self::append(path, entry)};
+            if let Err(write_error) = result {
+                log::warn!("Could not save the input history to {}: {write_error}", path.display());
+            }
+        }
+    }
+
+    /// Returns the entry before the one currently recalled, if any
+    /// "current" is what was typed so far, given back by "next" later on
+    pub fn previous(&mut self, current: String) -> Option<&str>{
+        let position = match self.position {
+            Some(0) => return None,
+            Some(position) => position - 1,
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current;
+                self.entries.len() - 1
+            }
+        };
+
+        self.position = Some(position);
+        Some(&self.entries[position])
+    }
+
+    /// Returns the entry after the one currently recalled, or what was being typed past the newest one
+    /// None if nothing is being recalled
+    pub fn next(&mut self) -> Option<&str>{
+        let position = self.position? + 1;
+        if position < self.entries.len() {
+            self.position = Some(position);
+            Some(&self.entries[position])
+        } else {
+            self.position = None;
+            Some(&self.draft)
+        }
+    }
+
+    /// Writes a single entry at the end of the file at "path", creating it and its directory if needed
+    fn append(path: &Path, entry: &str) -> io::Result<()>{
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{entry}")
+    }
+
+    /// Replaces the contents of the file at "path" with "entries"
+    fn rewrite(path: &Path, entries: &[String]) -> io::Result<()>{
+        let mut contents = entries.join("\n");
+        contents.push('\n');
+        fs::write(path, contents)
+    }
+}
@@ -0,0 +1,94 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   The optional terminal modes the chat uses, left off wherever    #
+//   the terminal can't be expected to support them                  #
+//********************************************************************
+
+use std::io::IsTerminal;
+
+use crossterm::{event::{DisableBracketedPaste, DisableFocusChange, DisableMouseCapture, EnableBracketedPaste, EnableFocusChange}, execute};
+
+// Constants
+const UNSUPPORTED_TERMS: [&str; 1] = ["dumb"];    // Terminals which would print the mode sequences as garbage
+
+/// Which optional terminal modes are used. The chat works without any of them: scrolling with the keyboard only,
+/// pasted text coming in as typed keys and the chat always being considered focused
+#[derive(Debug, Clone, Copy)]
+pub struct TerminalFeatures{
+    /// Whether the mouse wheel may scroll the chat. Turned on and off by the chat itself
+    pub mouse_capture: bool,
+    pub bracketed_paste: bool,
+    pub focus_reporting: bool,
+}
+impl TerminalFeatures{
+    /// Every feature, except those named in DISABLE_TERMINAL_FEATURES, i.e. "mouse,paste,focus"
+    /// None at all if TERM is unset or known not to support them, or if the output isn't a terminal
+    pub fn from_env() -> Self{
+        let term = std::env::var("TERM").unwrap_or_default();
+        if term.is_empty() || UNSUPPORTED_TERMS.contains(&term.as_str()) || !std::io::stdout().is_terminal() {
+            return Self{ mouse_capture: false, bracketed_paste: false, focus_reporting: false };
+        }
+
+        let mut features = Self{ mouse_capture: true, bracketed_paste: true, focus_reporting: true };
+        let disabled = std::env::var("DISABLE_TERMINAL_FEATURES").unwrap_or_default();
+        for feature in disabled.split(',').map(str::trim).filter(|feature| !feature.is_empty()) {
+            match feature {
+                "mouse" => features.mouse_capture = false,
+                "paste" => features.bracketed_paste = false,
+                "focus" => features.focus_reporting = false,
+                _ => log::warn!("Ignoring unknown terminal feature \"{feature}\" in DISABLE_TERMINAL_FEATURES"),
+            }
+        }
+        features
+    }
+
+    /// Turns on bracketed paste and focus reporting. Those the terminal turns down are left off for the
+    /// rest of the session, and every feature which is not available is logged
+    pub fn enable(&mut self){
+        if self.bracketed_paste {
+            if let Err(paste_error) = execute!(std::io::stdout(), EnableBracketedPaste) {
+                log::warn!("Could not enable bracketed paste: {paste_error}");
+                self.bracketed_paste = false;
+            }
+        }
+        if self.focus_reporting {
+            if let Err(focus_error) = execute!(std::io::stdout(), EnableFocusChange) {
+                log::warn!("Could not enable focus reporting: {focus_error}");
+                self.focus_reporting = false;
+            }
+        }
+
+        let unavailable: Vec<&str> = [(self.mouse_capture, "mouse capture"), (self.bracketed_paste, "bracketed paste"), (self.focus_reporting, "focus reporting")]
+            .into_iter()
+            .filter(|(available, _)| !available)
+            .map(|(_, name)| name)
+            .collect();
+        if !unavailable.is_empty() {
+            log::info!("Terminal features not in use: {}", unavailable.join(", "));
+        }
+    }
+
+    /// Turns off what "enable" turned on, along with mouse capture
+    pub fn disable(&self){
+        if self.mouse_capture {
+            if let Err(mouse_error) = execute!(std::io::stdout(), DisableMouseCapture) {
+                log::warn!("Could not unbind the scroll wheel: {mouse_error}");
+            }
+        }
+        if self.focus_reporting {
+            if let Err(focus_error) = execute!(std::io::stdout(), DisableFocusChange) {
+                log::warn!("Could not disable focus reporting: {focus_error}");
+            }
+        }
+        if self.bracketed_paste {
+            if let Err(paste_error) = execute!(std::io::stdout(), DisableBracketedPaste) {
+                log::warn!("Could not disable bracketed paste: {paste_error}");
+            }
+        }
+    }
+}
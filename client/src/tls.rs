@@ -0,0 +1,125 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Optional certificate pinning for wss:// connections             #
+//********************************************************************
+
+use std::sync::Arc;
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::{tungstenite::Error, Connector};
+
+// Constants
+const PIN_MISMATCH: &str = "server certificate does not match the pinned fingerprint";
+const FINGERPRINT_LENGTH: usize = 32;
+
+/// Verifies the server certificate as usual, then additionally requires
+/// its SHA-256 fingerprint to match the pinned one
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        if Sha256::digest(end_entity.as_ref()).as_slice() != self.fingerprint.as_slice() {
+            return Err(rustls::Error::General(PIN_MISMATCH.to_string()));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Parses a SHA-256 fingerprint written in hex, optionally separated by colons,
+/// i.e. "AB:CD:..." or "abcd..."
+fn parse_fingerprint(pin: &str) -> Result<Vec<u8>, String> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    if hex.len() != FINGERPRINT_LENGTH * 2 {
+        return Err(format!("expected {FINGERPRINT_LENGTH} hex bytes, got \"{pin}\""));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or(format!("invalid hex in \"{pin}\""))
+        })
+        .collect()
+}
+
+/// Builds a TLS connector which only accepts the server whose
+/// certificate matches the given fingerprint
+pub fn pinned_connector(pin: &str) -> Result<Connector, String> {
+    let fingerprint = parse_fingerprint(pin)?;
+
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+    };
+    let inner = WebPkiServerVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(|err| err.to_string())?;
+
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { inner, fingerprint }))
+        .with_no_client_auth();
+
+    Ok(Connector::Rustls(Arc::new(config)))
+}
+
+/// Whether a connection error was caused by a certificate pin mismatch
+pub fn is_pin_mismatch(error: &Error) -> bool {
+    match error {
+        Error::Io(io_error) => matches!(
+            io_error.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()),
+            Some(rustls::Error::General(reason)) if reason == PIN_MISMATCH
+        ),
+        _ => false,
+    }
+}
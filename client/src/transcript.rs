@@ -0,0 +1,57 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Saving the chat history to a file, through "/save"              #
+//********************************************************************
+
+use std::{fs::File, io::{self, BufWriter, Write}, path::Path, time::SystemTime};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use shared::{ClientMessage, MessageKind, Table};
+
+// Constants
+const TEXT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Writes every message in "history" to the file at "path", returning how many were written
+/// Paths ending in ".json" or ".jsonl" get one JSON object per line, anything else gets plain text
+/// Times are written as wall-clock times, since relative ones would be meaningless later on
+pub fn save(path: &Path, history: &[ClientMessage]) -> io::Result<usize> {
+    let as_json = path.extension().is_some_and(|extension| extension == "json" || extension == "jsonl");
+    let mut file = BufWriter::new(File::create(path)?);
+
+    for message in history {
+        if as_json {
+            let line = serde_json::json!({
+                "username": message.get_username(),
+                "kind": message.get_kind(),
+                "sent_at": sent_at(message).to_rfc3339_opts(SecondsFormat::Secs, true),
+                "body": message.get_message(),
+            });
+            writeln!(file, "{line}")?;
+        } else {
+            let time = sent_at(message).with_timezone(&Local).format(TEXT_TIME_FORMAT);
+            let mut body = message.get_message();
+            if message.get_kind() == MessageKind::Table {
+                if let Ok(table) = Table::parse(&body) {
+                    body = format!("\n{}", table.to_text());
+                }
+            }
+            writeln!(file, "[{time}] {}: {body}", message.get_username())?;
+        }
+    }
+
+    file.flush()?;
+    Ok(history.len())
+}
+
+/// The wall-clock time at which "message" was created
+fn sent_at(message: &ClientMessage) -> DateTime<Utc> {
+    SystemTime::now()
+        .checked_sub(message.get_timestamp().elapsed())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .into()
+}
@@ -6,44 +6,143 @@
 // Date: 2025                                                        #
 //********************************************************************
 
-use std::{cmp::min, io::Error, sync::Arc};
+use std::{cmp::min, collections::HashMap, io::Error, path::PathBuf, process::Stdio, sync::Arc, time::{Duration, Instant}};
 
-use crossterm::event::{self};
+use crossterm::{cursor::Show, event::{self, DisableMouseCapture, EnableMouseCapture}, execute, style::Print, terminal::{disable_raw_mode, LeaveAlternateScreen, SetTitle}};
 use futures_util::StreamExt;
 use ratatui::{
-    layout::{Constraint, Flex, Layout, Margin, Rect}, style::{Color, Style}, text::Line, widgets::{Block, BorderType, Borders, Padding, Paragraph}, DefaultTerminal
+    buffer::Buffer, layout::{Constraint, Flex, Layout, Margin, Rect}, style::{Color, Modifier, Style}, text::{Line, Span, StyledGrapheme, Text},
+    widgets::{Block, BorderType, Borders, Padding, Paragraph, Row, Table, Widget}, DefaultTerminal
 };
-use shared::ClientMessage;
+use shared::{validate_username, ClientMessage, Hello, MessageKind, CLIENT_USERNAME, ROOM_READ_ONLY};
 use tokio::{
+    process::Command,
     select,
-    sync::{mpsc::{UnboundedReceiver, UnboundedSender}, Mutex},
+    sync::{mpsc::{error::TrySendError, Receiver, Sender}, Mutex},
+    time::{interval, MissedTickBehavior},
 };
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-use crate::handlers::{handle_input_event, HandlingSignal};
+use crate::{
+    commands::{command_name, help, LocalCommand, COMMAND_PREFIX, CONFIRM_COMMAND},
+    config::{Config, NotifyOn},
+    handlers::{clear_history, handle_input_event, Directory, HandlingSignal, InputBuffer, Notification, Outgoing},
+    recall::InputHistory,
+    transcript,
+};
 
 // Constants
 const MAX_MESSAGES_ON_SCREEN: u8 = 8;      // Maximum number of messages on screen
 const PADDING_INSIDE: Padding = Padding::new(1, 1, 0, 0);
-const CURSOR_CHAR: &str = "_";
-const CLIENT_USERNAME: &str = "You";
-const SYSTEM_USERNAME: &str = "SYSTEM";
+const CURSOR_BLINK_INTERVAL: Duration = Duration::from_millis(500);  // Each half of a blink, if CURSOR_BLINK
+pub const SPINNER_INTERVAL: Duration = Duration::from_millis(100);   // Between the frames of the connection spinner
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+const SYSTEM_ICON: &str = "ℹ";
+const WINDOW_TITLE: &str = "Chatey";
+const BELL_CHAR: char = '\x07';
+const CODE_FENCE: &str = "```";
+const CODE_STYLE: Style = Style::new().fg(Color::White).bg(Color::DarkGray);
+const TAB_WIDTH: usize = 4;
+const WARNING_STYLE: Style = Style::new().fg(Color::Yellow).bg(Color::Black);
+const DIVIDER_STYLE: Style = Style::new().fg(Color::LightRed).bg(Color::Black);
+const FULL_WIDTH: usize = 3;               // Position index of widgets spanning all of [left, mid, right]
+const MIN_CLIPPED_HEIGHT: u16 = 3;         // Rows a message cut off at the top needs to show more than its borders
+const SIDEBAR_WIDTH: u16 = 28;
+const MAX_CHAT_WIDTH: u16 = 100;           // Keeps lines readable on wide terminals...
+const MIN_CHAT_WIDTH: u16 = 60;            // ...and the sidebars are dropped rather than squeeze it below this
+const USERNAME_COLORS: [Color; 12] = [
+    Color::Red, Color::Green, Color::Yellow, Color::Blue, Color::Magenta, Color::Cyan,
+    Color::LightRed, Color::LightGreen, Color::LightYellow, Color::LightBlue, Color::LightMagenta, Color::LightCyan,
+];
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+const MENTION_PREFIX: char = '@';
+const SIDEBAR_STYLE: Style = Style::new().fg(Color::Gray).bg(Color::Black);
+const TABLE_HEADER_STYLE: Style = Style::new().add_modifier(Modifier::BOLD);
+const EXPIRY_TICK: Duration = Duration::from_secs(1);   // How often disappearing messages count down
+const DRAW_TIME_WEIGHT: f64 = 0.1;         // Of the latest frame in the moving average shown by "/debugstats"
+
+/// Anything drawn in the chat flow
+enum MessageWidget<'a>{
+    Text(Paragraph<'a>),
+    Table(Table<'a>),
+}
+impl Widget for &MessageWidget<'_>{
+    fn render(self, area: Rect, buf: &mut Buffer){
+        match self {
+            MessageWidget::Text(paragraph) => paragraph.render(area, buf),
+            MessageWidget::Table(table) => table.render(area, buf),
+        }
+    }
+}
 
+/// The frame of the connection spinner to show "elapsed" after it started
+pub fn spinner_frame(elapsed: Duration) -> char {
+    let frame = elapsed.as_millis() / SPINNER_INTERVAL.as_millis();
+    SPINNER_FRAMES[frame as usize % SPINNER_FRAMES.len()]
+}
+
+/// How the chat has been drawing, as shown by "/debugstats"
+#[derive(Default)]
+struct RenderStats{
+    frames: u64,
+    /// Moving average of the time spent building and drawing a frame
+    average_draw: Duration,
+}
+impl RenderStats{
+    fn record(&mut self, draw_time: Duration){
+        self.average_draw = match self.frames {
+            0 => draw_time,
+            _ => self.average_draw.mul_f64(1.0 - DRAW_TIME_WEIGHT) + draw_time.mul_f64(DRAW_TIME_WEIGHT),
+        };
+        self.frames += 1;
+    }
+}
 
 /// Runs the TUI loop and prints the latest messages in 'history'
-/// The loop awaits until a '()' notification is received via 'notify_rx'
+/// The loop awaits until a notification is received via 'notify_rx'
 /// The TUI will NOT be updated otherwise
+/// 'auto_message', if any, is sent as soon as the chat is joined
 pub async fn run_chat(
     mut terminal: DefaultTerminal,
     history: Arc<Mutex<Vec<ClientMessage>>>,
-    mut notifier_rx: UnboundedReceiver<()>,
-    input_tx: UnboundedSender<String>
+    directory: Arc<Mutex<Directory>>,
+    mut notifier_rx: Receiver<Notification>,
+    input_tx: Sender<Outgoing>,
+    config: Arc<Config>,
+    auto_message: Option<String>,
 ) -> Result<(), Error> {
 
-    let mut input_box = Vec::new();
-    let mut username = Vec::new();
+    let mut input_box = InputBuffer::default();
+    let mut recall = InputHistory::load(config.history_file.clone(), config.history_size);
+    let mut expiry_ticker = interval(EXPIRY_TICK);
+    expiry_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut blink_ticker = interval(CURSOR_BLINK_INTERVAL);
+    blink_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut cursor_visible = true;
+    let mut username = InputBuffer::default();
+    let mut username_offset = 0usize;
     let mut event_reader = event::EventStream::new();
     let mut scroll_movement = 0i8;
     let mut scroll_pos = 0usize;
+    let mut focused = true;
+    let mut unread = 0usize;
+    let mut do_not_disturb = false;
+    let mut show_counts = false;
+    let mut show_sidebars = config.wide_layout;
+    let mut mouse_capture = config.mouse_capture && config.terminal_features.mouse_capture;
+    if config.terminal_features.mouse_capture && !set_mouse_capture(mouse_capture) {
+        mouse_capture = false;
+    }
+    let mut pending_confirmation: Option<(String, Instant)> = None;   // A command awaiting "/yes", and when it was asked
+    let mut last_seen = 0usize;                // History length when the user last looked at the chat
+    let mut unread_from: Option<usize> = None; // History index of the first message which arrived while away
+    let mut read_activity: HashMap<String, u64> = HashMap::new();  // Room activity when the user last was in each room
+    let mut render_stats = RenderStats::default();
+    let mut last_scroll: Option<Instant> = None;
+    let mut reconnecting_since: Option<Instant> = None;   // Until main is connected again after "/reconnect"
+    let mut spinner_ticker = interval(SPINNER_INTERVAL);
+    spinner_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     // Create layouts
     let username_vert_layout = Layout::vertical([
@@ -55,60 +154,164 @@ pub async fn run_chat(
         Constraint::Percentage(50)
     ])
         .flex(Flex::Center);
-    let msg_input_layout = Layout::vertical([
-        Constraint::Percentage(90),
-        Constraint::Fill(1),
-    ]);
-    let msg_vertical_layout = Layout::vertical([
-        Constraint::Ratio(1, MAX_MESSAGES_ON_SCREEN.into());
-        MAX_MESSAGES_ON_SCREEN as usize
-    ]);
     let msg_horizontal_layout = Layout::horizontal([
         Constraint::Percentage(35),
         Constraint::Fill(1),
         Constraint::Percentage(35),
     ]);
+    let wide_layout = Layout::horizontal([
+        Constraint::Length(SIDEBAR_WIDTH),
+        Constraint::Max(MAX_CHAT_WIDTH),
+        Constraint::Length(SIDEBAR_WIDTH),
+    ])
+        .flex(Flex::SpaceBetween);
 
     // Prompt the user for a username
-    loop{
-        let username_block = Paragraph::new(username.iter().collect::<String>() + CURSOR_CHAR)
-            .block(Block::bordered()
-                .padding(PADDING_INSIDE)
-                .title_top(Line::from("Set a username").centered())
-            )
-            .style(Style::default().fg(Color::White).bg(Color::Black));
-
+    // Names are checked locally first, then by the server, which either lets the client in
+    // or turns the name down. Either way, the prompt keeps accepting input meanwhile
+    let mut username_error: Option<String> = None;
+    let mut awaiting_server = false;
+    let username_string = loop{
         let draw_result = terminal.draw(|frame|{
             let [_, username_vert_area, _] = username_vert_layout.areas(frame.area().inner(Margin::new(1, 1)));
             let [username_area] = username_horizontal_layout.areas(username_vert_area);
+
+            // Borders and padding take up 4 columns
+            let cursor = if cursor_visible {config.cursor_shape.symbol()} else {" "};
+            let visible_username = visible_input(&username, &mut username_offset, username_area.width.saturating_sub(4).into(), cursor);
+            let prompt_title = if awaiting_server {"Checking username..."} else {"Set a username"};
+            let username_block = Paragraph::new(visible_username)
+                .block(Block::bordered()
+                    .padding(PADDING_INSIDE)
+                    .title_top(Line::from(prompt_title).centered())
+                )
+                .style(Style::default().fg(Color::White).bg(Color::Black));
+
             frame.render_widget(username_block, username_area);
+
+            // Any error goes right beneath the input, if there is room for it
+            if let Some(error) = &username_error {
+                let error_area = Rect{ y: username_area.bottom(), height: 1, ..username_area }.intersection(frame.area());
+                frame.render_widget(Paragraph::new(error.as_str()).style(WARNING_STYLE).centered(), error_area);
+            }
         });
 
         // Deal with draw result
-        if draw_result.is_err() {
-            log::error!("Failed to render frame: {}", draw_result.unwrap_err());
+        if let Err(draw_error) = draw_result {
+            log::error!("Failed to render frame: {draw_error}");
+        }
+
+        select! {
+            _ = blink_ticker.tick(), if config.cursor_blink => cursor_visible = !cursor_visible,
+
+            // The server's answer ends up in the history
+            _ = notifier_rx.recv(), if awaiting_server => {
+                let mut history = history.lock().await;
+                if let Some(index) = history.iter().position(|client_message| client_message.get_kind() == MessageKind::UsernameRejected) {
+                    username_error = Some(history.remove(index).get_message());
+                    awaiting_server = false;
+                } else if history.iter().any(|client_message| client_message.get_kind() == MessageKind::Joined) {
+                    break username.to_text();
+                }
+            },
+
+            // Handle input
+            // Usernames are a single line, so newlines and other control chars are dropped as soon as they come in
+            event = event_reader.next() => match (handle_input_event(event, &mut username, &mut scroll_movement, &mut focused), username.remove_control(None)){
+                (_, removed) if removed > 0 => {
+                    username_error = Some(format!("Removed {removed} character(s): names are one line"));
+                },
+                (HandlingSignal::Continue | HandlingSignal::ToggleSidebars | HandlingSignal::ToggleMouse | HandlingSignal::RecallPrevious | HandlingSignal::RecallNext, _) => {},
+                (HandlingSignal::End, _) if awaiting_server => {},
+                (HandlingSignal::End, _) => {
+                    let candidate = username.to_text();
+                    match validate_username(&candidate) {
+                        Ok(()) => {
+                            let hello = Hello{ username: candidate, room: Some(config.room.clone()) };
+                            // A closed channel means main is gone along with the connection, so there is
+                            // nobody left to log in through. Leave quietly and let the terminal be restored
+                            match input_tx.try_send(Outgoing::Hello(hello)) {
+                                Ok(()) => {
+                                    username_error = None;
+                                    awaiting_server = true;
+                                },
+                                Err(TrySendError::Full(_)) => username_error = Some("Still connecting, try again in a moment".to_string()),
+                                Err(TrySendError::Closed(_)) => {
+                                    log::warn!("Could not send the username to main, as the connection is gone. Leaving the chat");
+                                    return Ok(());
+                                },
+                            }
+                        },
+                        Err(reason) => username_error = Some(reason),
+                    }
+                },
+                (HandlingSignal::Quit, _) => return Err(std::io::Error::other("")),
+            }
+        }
+    };
+
+    // The server forgets colors along with the connection
+    if let Some(color) = &config.color {
+        if input_tx.try_send(Outgoing::Text(format!("/color {color}"))).is_err() {
+            log::warn!("Could not send the color back to main");
         }
+    }
 
-        // Handle input
-        match handle_input_event(event_reader.next().await, &mut username, &mut scroll_movement){
-            HandlingSignal::Continue => continue,
-            HandlingSignal::End => break,
-            HandlingSignal::Quit => return Err(std::io::Error::other("")),
+    // Greet the room, as if typed by the user
+    if let Some(auto_message) = auto_message {
+        if input_tx.try_send(Outgoing::Text(auto_message.clone())).is_err() {
+            log::warn!("Could not send the automatic message back to main");
+        } else if !auto_message.starts_with(COMMAND_PREFIX) {
+            history.lock().await.push(ClientMessage::new(CLIENT_USERNAME.to_string(), auto_message));
         }
     }
 
-    // Send username to server
-    let username_string = username.iter().collect::<String>();
-    if input_tx.send(username_string.clone()).is_err(){
-        log::error!("Could not send username message back to main");
-        return Err(std::io::Error::other(""))
-    };
+    // Messages are counted as new by how much the history grew since, rather than by notifications, which may be dropped
+    let mut counted = history.lock().await.len();    // History length once the latest new messages were counted
 
     // Main chat loop
-    let chat_title = format!("Logged in as {username_string}");
+    set_window_title(unread);
     loop {
-        // Determine the scrolling position 
-        let history_size = history.lock().await.len();
+        let frame_started = Instant::now();
+
+        // The history is locked once per frame, so that the scrolling position and the messages drawn
+        // agree even if a message arrives meanwhile. It is let go of before drawing
+        let colors = directory.lock().await.colors.clone();
+        let history_guard = history.lock().await;
+
+        // Determine the scrolling position, the current username and the current room,
+        // which the server may have changed since the prompt
+        let latest_of_kind = |kind: MessageKind| history_guard
+            .iter()
+            .rev()
+            .find(|client_message| client_message.get_kind() == kind)
+            .map(|client_message| client_message.get_message());
+        let history_size = history_guard.len();
+        let current_username = latest_of_kind(MessageKind::Rename).unwrap_or(username_string.clone());
+        let current_room = latest_of_kind(MessageKind::Joined).unwrap_or(config.room.clone());
+
+        // Joining a room resets whether the client may post
+        let read_only = history_guard
+            .iter()
+            .rev()
+            .find(|client_message| matches!(client_message.get_kind(), MessageKind::Joined | MessageKind::RoomAccess))
+            .is_some_and(|client_message| client_message.get_kind() == MessageKind::RoomAccess && client_message.get_message() == ROOM_READ_ONLY);
+        if focused && unread_from.is_none() {
+            last_seen = history_size;
+        }
+        let mut chat_title = format!("Logged in as {current_username} in #{current_room}");
+        if do_not_disturb {
+            chat_title += " (do not disturb)";
+        }
+        if !mouse_capture && config.terminal_features.mouse_capture {
+            chat_title += " (mouse off, Ctrl+T to scroll with it again)";
+        }
+        if let Some(since) = reconnecting_since {
+            chat_title += &match config.connection_spinner {
+                true => format!(" ({} reconnecting)", spinner_frame(since.elapsed())),
+                false => " (reconnecting)".to_string(),
+            };
+        }
         let tmp_scroll_pos = (scroll_pos as i64) + (scroll_movement as i64);
         scroll_pos = tmp_scroll_pos.clamp(0, u16::MAX.into()) as usize;
         let max_acceptable = (history_size as i32 - MAX_MESSAGES_ON_SCREEN as i32).clamp(0, u16::MAX.into()) as usize;
@@ -119,31 +322,117 @@ pub async fn run_chat(
         let outer_block = Block::bordered()
             .padding(PADDING_INSIDE)
             .style(Style::default().fg(Color::White).bg(Color::Black))
-            .title_top(Line::from(chat_title.clone()).centered());
+            .title_top(Line::from(chat_title).centered());
 
-        // Create message blocks
-        let msg_blocks: Vec<(Paragraph, usize)> = history
-            .lock()
-            .await
+        // Messages are wrapped to the width of the column they are drawn in
+        let frame_area = terminal.size().map(|size| Rect::new(0, 0, size.width, size.height)).unwrap_or_default();
+        let (chat_area, _) = split_chat(&wide_layout, frame_area.inner(Margin::new(1, 1)), show_sidebars);
+        let columns: [Rect; 3] = msg_horizontal_layout.areas(Rect{ height: 1, ..chat_area });
+
+        // Create message blocks, along with the height they need
+        let mut msg_blocks: Vec<(MessageWidget, usize, u16)> = history_guard
             .iter()
+            .enumerate()
             .rev()
             .skip(scroll_pos)
             .take(MAX_MESSAGES_ON_SCREEN as usize)
-            .map(|client_message| {
+            .map(|(index, client_message)| {
+                // Anything which isn't chat comes from the server, whatever the username says
+                let is_system = !matches!(client_message.get_kind(), MessageKind::Chat | MessageKind::Table);
                 let position_index: usize = match client_message.get_username().as_str(){
+                    _ if is_system => 1,
                     CLIENT_USERNAME => 2,
-                    SYSTEM_USERNAME => 1,
+                    // Tables and disappearing messages are only shown once the server echoes them back,
+                    // under the user's own name
+                    username if username == current_username => 2,
                     _ => 0
                 };
 
                 // Define the message title (at the bottom of the paragraph)
-                let mut title = Line::from(client_message.get_metadata());
+                let mut metadata = config.timestamps.metadata(client_message);
+                if let Some(time_left) = client_message.time_left() {
+                    metadata += &format!(" (disappears in {} s)", time_left.as_secs_f32().ceil());
+                }
+                if show_counts && !is_system {
+                    metadata += &format!(" ({} chars)", client_message.get_message().graphemes(true).count());
+                }
+                let mut title = Line::from(metadata);
                 if position_index == 0 {title = title.left_aligned()}
                 else if position_index == 1 {title = title.centered()}
                 else if position_index == 2 {title = title.right_aligned()}
+                if !is_system {
+                    let sender = if position_index == 2 {current_username.clone()} else {client_message.get_username()};
+                    title = title.style(Style::default().fg(color_for_username(&sender, &colors)));
+                }
+
+                // Only the first message of a group shows who sent it
+                let in_group = index > 0 && config.group_window.is_some_and(|window| continues_group(&history_guard[index - 1], client_message, window));
+                if in_group {
+                    title = Line::default();
+                }
 
                 // Define the paragraph
-                let mut parag = Paragraph::new(client_message.get_message().to_owned())
+                let text = match client_message.get_kind() {
+                    MessageKind::Rename => format!("You are now known as {}", client_message.get_message()),
+                    MessageKind::Joined => format!("You are now in #{}", client_message.get_message()),
+                    MessageKind::RoomAccess if client_message.get_message() == ROOM_READ_ONLY => "Only admins may post in this room".to_string(),
+                    MessageKind::RoomAccess => "You may post in this room".to_string(),
+                    MessageKind::Invite => match shared::from_json_limited::<shared::Invite>(&client_message.get_message()) {
+                        Ok(invite) => {
+                            let once = if invite.token.is_some() {". It lets one person in, within a day"} else {""};
+                            let link = shared::Invite{ server: config.server_url.clone(), ..invite.clone() }.to_link();
+                            format!("Share {link} to invite someone into #{}{once}", invite.room)
+                        }
+                        Err(_) => client_message.get_message(),
+                    },
+                    _ => client_message.get_message(),
+                };
+
+                // System messages are meta-events rather than chat: no bubble, muted and with an icon
+                // Padding takes up 2 columns, borders another 2
+                let column_width = usize::from(columns[position_index].width);
+                if is_system {
+                    let body = wrap_text(Text::from(format!("{SYSTEM_ICON} {text}")), column_width.saturating_sub(2));
+                    let height = body.height() as u16 + 1;
+                    let parag = Paragraph::new(body)
+                        .block(Block::default()
+                            .title_bottom(title)
+                            .padding(PADDING_INSIDE),
+                        )
+                        .style(Style::default().fg(Color::DarkGray).bg(Color::Black).add_modifier(Modifier::ITALIC))
+                        .centered();
+
+                    return (MessageWidget::Text(parag), position_index, height);
+                }
+
+                // Tables which can't be parsed are shown as they came instead
+                if client_message.get_kind() == MessageKind::Table {
+                    if let Ok(table) = shared::Table::parse(&text) {
+                        // Borders take up 2 rows, and the header another one
+                        let height = table.rows.len() as u16 + 3;
+                        let widths: Vec<Constraint> = (0..table.headers.len())
+                            .map(|column| {
+                                let width = std::iter::once(&table.headers).chain(&table.rows).map(|row| row[column].width()).max().unwrap_or(0);
+                                Constraint::Length(width as u16)
+                            })
+                            .collect();
+                        let widget = Table::new(table.rows.into_iter().map(Row::new), widths)
+                            .header(Row::new(table.headers).style(TABLE_HEADER_STYLE))
+                            .column_spacing(2)
+                            .block(Block::bordered()
+                                .title_bottom(title)
+                                .padding(PADDING_INSIDE)
+                                .border_type(BorderType::Rounded),
+                            )
+                            .style(Style::default().fg(Color::White).bg(Color::Black));
+                        return (MessageWidget::Table(widget), position_index, height);
+                    }
+                }
+
+                // Borders take up 2 rows
+                let body = wrap_text(format_body(&text), column_width.saturating_sub(4));
+                let height = body.height() as u16 + 2;
+                let mut parag = Paragraph::new(body)
                     .block(Block::bordered()
                         .title_bottom(title)
                         .padding(PADDING_INSIDE)
@@ -151,78 +440,812 @@ pub async fn run_chat(
                     )
                     .style(Style::default().fg(Color::White).bg(Color::Black));
 
-                if position_index == 1 {parag = parag.centered()} 
-                else if position_index == 2 {parag = parag.right_aligned()} 
+                if position_index == 2 {parag = parag.right_aligned()} 
 
-                (parag, position_index)
+                (MessageWidget::Text(parag), position_index, height)
             })
             .collect();
+        let any_expiring = history_guard.iter().any(|client_message| client_message.time_left().is_some());
+        drop(history_guard);
+
+        // Mark where the messages which arrived while away begin, if it is on screen
+        // Blocks are ordered from the newest, so the divider goes right after the unread ones
+        if let Some(first_unread) = unread_from.filter(|first_unread| *first_unread < history_size) {
+            let divider_index = (history_size - scroll_pos).saturating_sub(first_unread);
+            if divider_index <= msg_blocks.len() {
+                let divider = Paragraph::new("")
+                    .block(Block::default()
+                        .borders(Borders::TOP)
+                        .title(Line::from(" new messages below ").centered()),
+                    )
+                    .style(DIVIDER_STYLE);
+                msg_blocks.insert(divider_index, (MessageWidget::Text(divider), FULL_WIDTH, 1));
+            }
+        }
+
+        // Whatever is posted in the room being viewed counts as read
+        let read = directory.lock().await.activity.get(&current_room).copied().unwrap_or_default();
+        read_activity.insert(current_room.clone(), read);
 
-        // Create the input block
-        let input_string = input_box.iter().collect::<String>() + CURSOR_CHAR;
-        let input_block = Paragraph::new(input_string)
-            .block(
-                Block::default()
-                    .borders(Borders::TOP)
-                    .padding(PADDING_INSIDE),
-            )
-            .style(Style::default().fg(Color::White).bg(Color::Black));
+        // The room list and the roster, for wide terminals
+        // Rooms the user has been in this session show how many messages were posted there since
+        let sidebars = if show_sidebars {
+            let directory = directory.lock().await;
+            let room_lines: Vec<Line> = directory.rooms
+                .iter()
+                .map(|room| {
+                    let unread = read_activity
+                        .get(room)
+                        .map(|read| directory.activity.get(room).copied().unwrap_or_default().saturating_sub(*read))
+                        .unwrap_or_default();
+                    if *room == current_room {
+                        Line::from(format!("> #{room}")).style(Style::default().fg(Color::White).add_modifier(Modifier::BOLD))
+                    } else if unread > 0 {
+                        Line::from(format!("  #{room} ({unread})")).style(Style::default().add_modifier(Modifier::BOLD))
+                    } else {
+                        Line::from(format!("  #{room}"))
+                    }
+                })
+                .collect();
+            let rooms = Paragraph::new(room_lines)
+                .block(Block::bordered().border_type(BorderType::Rounded).title(Line::from(" Rooms ").centered()))
+                .style(SIDEBAR_STYLE);
+            let roster = Paragraph::new(directory.roster.iter().map(|username| Line::from(username.clone())).collect::<Vec<_>>())
+                .block(Block::bordered()
+                    .border_type(BorderType::Rounded)
+                    .title(Line::from(format!(" In #{current_room} ({}) ", directory.roster.len())).centered())
+                    .padding(PADDING_INSIDE)
+                )
+                .style(SIDEBAR_STYLE);
+            Some((rooms, roster))
+        } else {
+            None
+        };
 
         // Draw a frame
         let draw_result = terminal.draw(|frame| {
             // --- Creating areas ---
             // Outer frame
             let outer = frame.area();
+            frame.render_widget(&outer_block, outer);
+
+            // Wide terminals fit the sidebars on either side of a chat column of bounded width
+            // Narrower ones only show the chat
+            let (chat_area, sidebar_areas) = split_chat(&wide_layout, outer.inner(Margin::new(1, 1)), sidebars.is_some());
+            if let (Some((rooms, roster)), Some((rooms_area, roster_area))) = (&sidebars, sidebar_areas) {
+                frame.render_widget(rooms, rooms_area);
+                frame.render_widget(roster, roster_area);
+            }
 
-            // Devide outer into a messages box and an input box
-            let [msg_area, input_area] = msg_input_layout.areas(outer.inner(Margin::new(1, 1)));
+            // Devide the chat into a messages box and an input box, whose padding takes up 2 columns
+            let cursor = if cursor_visible {config.cursor_shape.symbol()} else {" "};
+            let input_lines = wrapped_input(&input_box, chat_area.width.saturating_sub(2).into(), config.input_max_lines, cursor);
+            let (msg_area, input_area) = split_input(chat_area, input_lines.len());
 
-            // Devide the messages box into vertival parts
-            let vertical_areas: [Rect; MAX_MESSAGES_ON_SCREEN as usize] =
-                msg_vertical_layout.areas(msg_area);
+            // Messages share the box evenly, stacked from the bottom, unless they need more rows
+            let base_height = msg_area.height / MAX_MESSAGES_ON_SCREEN as u16;
 
-            // The final message areas are an array of [left, mid, right] areas
-            // Each message area is meant to be used with a single of the three areas
-            let mut msg_areas: Vec<[Rect; 3]> = Vec::new();
-            for v in vertical_areas.iter().rev() {
-                msg_areas.push(msg_horizontal_layout.areas(*v));
+            // Read-only rooms still take commands, but look disabled
+            let mut input_block = Block::default()
+                .borders(Borders::TOP)
+                .padding(PADDING_INSIDE);
+            let mut input_style = Style::default().fg(Color::White).bg(Color::Black);
+            if read_only {
+                input_block = input_block.title(Line::from(" read-only, commands only ").centered());
+                input_style = input_style.fg(Color::DarkGray);
             }
+            let input_block = Paragraph::new(input_lines)
+                .block(input_block)
+                .style(input_style);
 
             // Draw each widget
-            frame.render_widget(&outer_block, outer);
             frame.render_widget(input_block, input_area);
-            for (i, (msg, index)) in msg_blocks.iter().enumerate() {
-                frame.render_widget(msg, msg_areas[i][*index]);
+            let heights = msg_blocks
+                .iter()
+                .map(|(_, index, height)| if *index == FULL_WIDTH {*height} else {(*height).max(base_height)});
+            for ((msg, index, _), row) in msg_blocks.iter().zip(stack_messages(heights, msg_area)) {
+                // Each message is drawn in one of the [left, mid, right] areas of its row
+                let columns: [Rect; 3] = msg_horizontal_layout.areas(row);
+                let area = if *index == FULL_WIDTH {row} else {columns[*index]};
+                frame.render_widget(msg, area);
             }
         });
 
         // Deal with draw result
-        if draw_result.is_err() {
-            log::error!("Failed to render frame: {}", draw_result.unwrap_err());
+        render_stats.record(frame_started.elapsed());
+        if let Err(draw_error) = draw_result {
+            log::error!("Failed to render frame: {draw_error}");
         }
 
         // Wait for an event to trigger a new TUI frame
         select! {
+            _ = blink_ticker.tick(), if config.cursor_blink => cursor_visible = !cursor_visible,
+            _ = spinner_ticker.tick(), if config.connection_spinner && reconnecting_since.is_some() => {},
+
             // Wait for a change in history notification via "notify_rx"
-            _ = notifier_rx.recv() => continue,
+            // Only notify the user if they are not looking at the chat nor asked not to be disturbed
+            // Count down disappearing messages, removing those which are due
+            _ = expiry_ticker.tick(), if any_expiring => {
+                let mut history = history.lock().await;
+                let expired: Vec<usize> = history
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, client_message)| client_message.is_expired())
+                    .map(|(index, _)| index)
+                    .collect();
+
+                // Positions past the removed messages move back along with them
+                let shift = |position: usize| position - expired.iter().filter(|index| **index < position).count();
+                unread_from = unread_from.map(shift);
+                last_seen = shift(last_seen);
+                counted = shift(counted);
+                let mut index = 0;
+                history.retain(|_| {
+                    index += 1;
+                    !expired.contains(&(index - 1))
+                });
+            },
+
+            notification = notifier_rx.recv() => {
+                // Notifications pile up while a frame is drawn, so whatever else is pending is taken in
+                // as well, and a burst is drawn once rather than once per message
+                let pending = std::iter::from_fn(|| notifier_rx.try_recv().ok().map(Some));
+                let mut cleared = false;
+                for notification in std::iter::once(notification).chain(pending) {
+                    match notification {
+                        Some(Notification::Connected) => reconnecting_since = None,
+                        Some(Notification::Cleared) => cleared = true,
+                        // Only need a new frame, as new messages are counted below
+                        _ => {},
+                    }
+                }
+
+                // Positions in the history no longer mean anything. What came after the clearing can't be told apart
+                // from what it kept, so none of it counts as new
+                let history_size = history.lock().await.len();
+                let new_messages = if cleared {0} else {history_size.saturating_sub(counted)};
+                counted = history_size;
+                if cleared {
+                    scroll_pos = 0;
+                    last_seen = 0;
+                    unread_from = None;
+                }
+                if new_messages == 0 {
+                    continue;
+                }
+                if !focused && unread_from.is_none() {
+                    unread_from = Some(last_seen);
+                }
+
+                // Someone reading back through the history keeps their place, with the new messages marked below,
+                // until they are back at the bottom and have stopped scrolling for a while
+                let reading = scroll_pos > 0 || last_scroll.is_some_and(|at| at.elapsed() < config.scroll_pause);
+                if reading {
+                    scroll_pos += new_messages;
+                    if unread_from.is_none() {
+                        unread_from = Some(history_size - new_messages);
+                    }
+                }
+                if !focused && !do_not_disturb {
+                    unread += new_messages;
+                    set_window_title(unread);
+                    ring_bell();
+
+                    // Once per burst as well, unless only mentions of this user should trigger it
+                    if let Some(command) = &config.notify_command {
+                        let mentioned = history
+                            .lock()
+                            .await
+                            .iter()
+                            .rev()
+                            .take(new_messages)
+                            .filter(|client_message| client_message.get_kind() == MessageKind::Chat)
+                            .any(|client_message| mentioned_usernames(&client_message.get_message()).any(|username| username == current_username));
+                        if config.notify_on == NotifyOn::Any || mentioned {
+                            run_notify_command(command);
+                        }
+                    }
+                }
+            },
 
             // Wait for a key to be pressed
-            event = event_reader.next() => match handle_input_event(event, &mut input_box, &mut scroll_movement){
-                HandlingSignal::Continue => continue,
-                HandlingSignal::End => {
-                    let input_string: String = input_box.iter().collect();
-                    if input_tx.send(input_string.clone()).is_err(){
-                        log::error!("Could not send input message back to main")
-                    };
+            event = event_reader.next() => {
+                let was_focused = focused;
+                let signal = handle_input_event(event, &mut input_box, &mut scroll_movement, &mut focused);
+                if scroll_movement != 0 {
+                    last_scroll = Some(Instant::now());
+                }
+
+                // Pasted lines stay lines of a single message, unlike in the username prompt
+                input_box.remove_control_but_lines();
+
+                // The divider stays until the user does something after coming back
+                if was_focused && focused {
+                    unread_from = None;
+                }
+
+                match signal {
+                    HandlingSignal::Continue => {
+                        // Regaining focus means the new messages have been seen
+                        if focused && unread > 0 {
+                            unread = 0;
+                            set_window_title(unread);
+                        }
+                    },
+                    HandlingSignal::End => {
+                        // Blank inputs would only show as empty bubbles, so Enter just does nothing then
+                        let mut input_string = input_box.to_text().trim_end().to_string();
+                        if input_string.is_empty() {
+                            continue;
+                        }
+                        recall.push(&input_string);
+
+                        // Commands which need confirming are held until "/yes", and anything else cancels them
+                        if let Some((pending, asked_at)) = pending_confirmation.take() {
+                            if input_string == CONFIRM_COMMAND && asked_at.elapsed() <= CONFIRM_TIMEOUT {
+                                input_string = pending;
+                            } else {
+                                push_local(&history, &mut counted, ClientMessage::system(format!("Cancelled {pending}"))).await;
+                                if input_string == CONFIRM_COMMAND {
+                                    input_box.clear();
+                                    continue;
+                                }
+                            }
+                        } else if input_string == CONFIRM_COMMAND {
+                            push_local(&history, &mut counted, ClientMessage::system("Nothing to confirm".to_string())).await;
+                            input_box.clear();
+                            continue;
+                        } else if command_name(&input_string).is_some_and(|name| config.confirm_commands.iter().any(|command| command == name)) {
+                            let notice = format!("Type {CONFIRM_COMMAND} within {} s to confirm {input_string}", CONFIRM_TIMEOUT.as_secs());
+                            push_local(&history, &mut counted, ClientMessage::system(notice)).await;
+                            pending_confirmation = Some((input_string, Instant::now()));
+                            input_box.clear();
+                            continue;
+                        }
+
+                        // Local commands never reach the server
+                        if let Some(command) = LocalCommand::parse(&input_string) {
+                            let notice = match command {
+                                LocalCommand::DoNotDisturb => {
+                                    do_not_disturb = !do_not_disturb;
+                                    if do_not_disturb {"Do not disturb is on"} else {"Do not disturb is off"}.to_string()
+                                }
+                                LocalCommand::Count => {
+                                    show_counts = !show_counts;
+                                    if show_counts {"Message lengths are shown"} else {"Message lengths are hidden"}.to_string()
+                                }
+                                LocalCommand::Quit => return Err(std::io::Error::other("")),
+                                LocalCommand::Clear => {
+                                    let mut history = history.lock().await;
+                                    clear_history(&mut history);
+                                    counted = history.len();
+                                    scroll_pos = 0;
+                                    last_seen = 0;
+                                    unread_from = None;
+                                    "Cleared the history".to_string()
+                                }
+                                LocalCommand::Help(topic) => help(&topic),
+                                LocalCommand::DebugStats => format!(
+                                    "Frames drawn: {}\nAverage draw time: {:.2} ms\nHistory: {} message(s)\nPending redraws: {} of {}\nPending inputs: {} of {}",
+                                    render_stats.frames,
+                                    render_stats.average_draw.as_secs_f64() * 1000.0,
+                                    history.lock().await.len(),
+                                    notifier_rx.len(), notifier_rx.max_capacity(),
+                                    input_tx.max_capacity() - input_tx.capacity(), input_tx.max_capacity(),
+                                ),
+                                LocalCommand::Reconnect => {
+                                    // Handled by main with the same TUI and history, so only the server needs
+                                    // to be told who this is again, and that the color is still wanted
+                                    let hello = Hello{ username: current_username.clone(), room: Some(current_room.clone()) };
+                                    if input_tx.try_send(Outgoing::Reconnect(hello)).is_err() {
+                                        "Still sending earlier messages, try again in a moment".to_string()
+                                    } else {
+                                        if let Some(color) = &config.color {
+                                            if input_tx.try_send(Outgoing::Text(format!("/color {color}"))).is_err() {
+                                                log::warn!("Could not send the color back to main");
+                                            }
+                                        }
+                                        reconnecting_since = Some(Instant::now());
+                                        "Reconnecting...".to_string()
+                                    }
+                                }
+                                LocalCommand::Save(path) if path.is_empty() => "Usage: /save <path>".to_string(),
+                                LocalCommand::Save(path) => {
+                                    // Written from a snapshot on a blocking thread, so that neither a slow disk nor a long
+                                    // history holds up the chat or the messages coming in meanwhile
+                                    let snapshot = history.lock().await.clone();
+                                    let file_path = PathBuf::from(&path);
+                                    let saved = tokio::task::spawn_blocking(move || transcript::save(&file_path, &snapshot))
+                                        .await
+                                        .unwrap_or_else(|join_error| Err(Error::other(join_error)));
+                                    match saved {
+                                        Ok(count) => format!("Saved {count} message(s) to {path}"),
+                                        Err(save_error) => {
+                                            log::warn!("Could not save the history to {path}: {save_error}");
+                                            format!("Could not save to {path}: {save_error}")
+                                        }
+                                    }
+                                }
+                            };
+                            push_local(&history, &mut counted, ClientMessage::system(notice)).await;
+                            input_box.clear();
+                            continue;
+                        }
+
+                        if read_only && !input_string.starts_with(COMMAND_PREFIX) {
+                            push_local(&history, &mut counted, ClientMessage::system("Only admins may post in this room".to_string())).await;
+                            continue;
+                        }
+
+                        // A full channel means the server is not keeping up, so the input is put back in the box
+                        // to be sent again later. A closed one means the connection was torn down, so stop and let
+                        // the terminal be restored rather than accept input going nowhere
+                        match input_tx.try_send(Outgoing::Text(input_string.clone())) {
+                            Ok(()) => {},
+                            Err(TrySendError::Full(_)) => {
+                                push_local(&history, &mut counted, ClientMessage::system("Still sending earlier messages, try again in a moment".to_string())).await;
+                                input_box.set(&input_string);
+                                continue;
+                            },
+                            Err(TrySendError::Closed(_)) => {
+                                log::warn!("Could not send input message back to main. Leaving the chat");
+                                return Ok(());
+                            },
+                        }
                     
-                    // Add input to history and clear input box
-                    history.lock().await.push(
-                        ClientMessage::new("You".to_string(), input_string)
-                    );
-                    input_box.clear();
-                },
-                HandlingSignal::Quit => return Err(std::io::Error::other("")),
+                        // Add input to history and clear input box
+                        // Commands are answered by the server instead
+                        if !input_string.starts_with(COMMAND_PREFIX) {
+                            let absent = absent_mentions(&input_string, &directory.lock().await.roster);
+                            push_local(&history, &mut counted, ClientMessage::new(CLIENT_USERNAME.to_string(), input_string)).await;
+
+                            // The message is sent anyway, but those mentioned won't see it live
+                            for username in absent {
+                                let notice = format!("@{username} is not in #{current_room} right now");
+                                push_local(&history, &mut counted, ClientMessage::system(notice)).await;
+                            }
+                        }
+                        input_box.clear();
+                    },
+                    HandlingSignal::Quit => return Err(std::io::Error::other("")),
+                    HandlingSignal::ToggleSidebars => show_sidebars = !show_sidebars,
+                    HandlingSignal::ToggleMouse if !config.terminal_features.mouse_capture => {
+                        push_local(&history, &mut counted, ClientMessage::system("The mouse can't scroll the chat in this terminal, use Page Up and Page Down".to_string())).await;
+                    },
+                    HandlingSignal::ToggleMouse => {
+                        if set_mouse_capture(!mouse_capture) {
+                            mouse_capture = !mouse_capture;
+                        }
+                    },
+                    HandlingSignal::RecallPrevious => {
+                        if let Some(entry) = recall.previous(input_box.to_text()) {
+                            input_box.set(entry);
+                        }
+                    },
+                    HandlingSignal::RecallNext => {
+                        if let Some(entry) = recall.next() {
+                            input_box.set(entry);
+                        }
+                    },
+                }
+            },
+        }
+    }
+}
+
+/// Adds a message of the client's own making to "history", keeping it out of the new messages counted from "counted"
+async fn push_local(history: &Mutex<Vec<ClientMessage>>, counted: &mut usize, client_message: ClientMessage) {
+    history.lock().await.push(client_message);
+    *counted += 1;
+}
+
+/// Gives the terminal back in the state it was found in, leaving the TUI's alternate screen and raw mode
+/// If that fails, each step is retried on its own, so that as little as possible is left garbled
+pub fn restore_terminal() {
+    let Err(restore_error) = ratatui::try_restore() else {
+        return;
+    };
+    log::error!("Could not restore the terminal: {restore_error}. Resetting it manually");
+
+    if let Err(raw_mode_error) = disable_raw_mode() {
+        log::error!("Could not disable raw mode: {raw_mode_error}");
+    }
+    if let Err(screen_error) = execute!(std::io::stdout(), LeaveAlternateScreen, Show) {
+        log::error!("Could not leave the alternate screen: {screen_error}");
+    }
+}
+
+/// Returns the color "username" is rendered in: the one they picked, or one derived from their name otherwise
+/// so that it stays the same everywhere and across sessions
+fn color_for_username(username: &str, colors: &HashMap<String, String>) -> Color {
+    if let Some(color) = colors.get(username).and_then(|color| color.parse().ok()) {
+        return color;
+    }
+
+    // FNV-1a, as the standard hasher is not guaranteed to be stable
+    let hash = username
+        .bytes()
+        .fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+    USERNAME_COLORS[hash as usize % USERNAME_COLORS.len()]
+}
+
+/// Returns the usernames mentioned as "@username" in "message" which are not in "roster", without duplicates
+/// Nobody is considered absent until the roster is known
+fn absent_mentions(message: &str, roster: &[String]) -> Vec<String> {
+    if roster.is_empty() {
+        return Vec::new();
+    }
+
+    let mut absent: Vec<String> = Vec::new();
+    for username in mentioned_usernames(message) {
+        if !roster.iter().any(|member| member == username) && !absent.iter().any(|name| name == username) {
+            absent.push(username.to_string());
+        }
+    }
+    absent
+}
+
+/// Returns the usernames mentioned as "@username" in "message", in order
+fn mentioned_usernames(message: &str) -> impl Iterator<Item = &str> {
+    message
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix(MENTION_PREFIX))
+        // Allow for punctuation right after the name, i.e. "@bob,"
+        .map(|username| username.trim_end_matches(|c: char| c.is_ascii_punctuation() && c != '_' && c != '-'))
+        .filter(|username| !username.is_empty())
+}
+
+/// Returns the part of "input" which fits in "width" columns, with the cursor drawn in place.
+/// "offset" is the index of the first visible char, kept between frames so the view only
+/// scrolls horizontally when the cursor would otherwise leave it
+fn visible_input(input: &InputBuffer, offset: &mut usize, width: usize, cursor_symbol: &str) -> String {
+    let chars = input.get_chars();
+    let cursor = input.get_cursor();
+
+    // One column is reserved for the cursor itself
+    let text_width = width.saturating_sub(1).max(1);
+    if cursor < *offset {
+        *offset = cursor;
+    } else if cursor >= *offset + text_width {
+        *offset = cursor + 1 - text_width;
+    }
+
+    let end = min(chars.len(), *offset + text_width);
+    let before: String = chars[*offset..cursor].iter().collect();
+    let after: String = chars[cursor..end].iter().collect();
+    before + cursor_symbol + &after
+}
+
+/// Splits "chat_area" into the messages box and, below it, an input box for "input_lines" lines
+/// The input takes its usual tenth of the chat, growing with what is typed, but never past half of it.
+/// Its top border takes up a row
+fn split_input(chat_area: Rect, input_lines: usize) -> (Rect, Rect) {
+    let input_height = (input_lines as u16 + 1)
+        .max(chat_area.height - chat_area.height * 9 / 10)
+        .min(chat_area.height / 2);
+    let [msg_area, input_area] = Layout::vertical([Constraint::Fill(1), Constraint::Length(input_height)]).areas(chat_area);
+    (msg_area, input_area)
+}
+
+/// Stacks messages which are "heights" rows tall, newest first, from the bottom of "area" up, returning their rows
+/// Only as many as fit are. The topmost one may be cut off, unless too little of it would be left to make sense of
+fn stack_messages(heights: impl IntoIterator<Item = u16>, area: Rect) -> Vec<Rect> {
+    let mut rows = Vec::new();
+    let mut bottom = area.bottom();
+    for height in heights {
+        let visible = height.min(bottom - area.top());
+        if visible == 0 || (visible < height && visible < MIN_CLIPPED_HEIGHT) {
+            break;
+        }
+        bottom -= visible;
+        rows.push(Rect{ y: bottom, height: visible, ..area });
+    }
+    rows
+}
+
+/// Returns the lines of "input" as wrapped to "width" columns, as wide as each char is displayed, with the cursor drawn in
+/// Line breaks in the input start a new line. Past "max_lines", only those up to the one with the cursor are
+/// returned, so that it stays visible
+fn wrapped_input(input: &InputBuffer, width: usize, max_lines: usize, cursor_symbol: &str) -> Vec<Line<'static>> {
+    let chars = input.get_chars();
+    let cursor = input.get_cursor();
+    let width = width.max(1);
+
+    let text = chars[..cursor].iter().map(char::to_string)
+        .chain(std::iter::once(cursor_symbol.to_string()))
+        .chain(chars[cursor..].iter().map(char::to_string));
+    let mut lines = vec![String::new()];
+    let mut line_width = 0;
+    let mut cursor_line = 0;
+    for (index, cell) in text.enumerate() {
+        if cell == "\n" {
+            lines.push(String::new());
+            line_width = 0;
+            continue;
+        }
+        let cell_width = cell.width();
+        if line_width + cell_width > width && line_width > 0 {
+            lines.push(String::new());
+            line_width = 0;
+        }
+        if index == cursor {
+            cursor_line = lines.len() - 1;
+        }
+        if let Some(line) = lines.last_mut() {
+            line.push_str(&cell);
+        }
+        line_width += cell_width;
+    }
+
+    let first = (cursor_line + 1).saturating_sub(max_lines);
+    lines.into_iter().skip(first).take(max_lines).map(Line::from).collect()
+}
+
+/// Whether "message" continues the group of "previous": both chat messages from the same sender, at most "window" apart
+fn continues_group(previous: &ClientMessage, message: &ClientMessage, window: Duration) -> bool {
+    previous.get_kind() == MessageKind::Chat
+        && message.get_kind() == MessageKind::Chat
+        && previous.get_username() == message.get_username()
+        && message.get_timestamp().saturating_duration_since(previous.get_timestamp()) <= window
+}
+
+/// Returns the area of the chat within "inner", along with those of the room list and the roster
+/// if the sidebars are shown and fit
+fn split_chat(wide_layout: &Layout, inner: Rect, show_sidebars: bool) -> (Rect, Option<(Rect, Rect)>) {
+    if show_sidebars && inner.width >= MIN_CHAT_WIDTH + 2 * SIDEBAR_WIDTH {
+        let [rooms_area, chat_area, roster_area] = wide_layout.areas(inner);
+        (chat_area, Some((rooms_area, roster_area)))
+    } else {
+        (inner, None)
+    }
+}
+
+/// Wraps every line of "text" to at most "width" columns, breaking between words where possible
+/// Words wider than a whole line, such as long URLs, are broken wherever the line ends instead
+fn wrap_text(text: Text<'static>, width: usize) -> Text<'static> {
+    let width = width.max(1);
+    let mut lines: Vec<Line> = Vec::new();
+
+    for line in text.lines {
+        if line.width() <= width {
+            lines.push(line);
+            continue;
+        }
+
+        let graphemes: Vec<StyledGrapheme> = line.spans.iter().flat_map(|span| span.styled_graphemes(Style::default())).collect();
+        let mut wrapped: Vec<StyledGrapheme> = Vec::new();
+        let mut wrapped_width = 0;
+        let mut start = 0;
+        while start < graphemes.len() {
+            // Words and the whitespace between them are laid out in one go
+            let is_space = graphemes[start].symbol.trim().is_empty();
+            let end = graphemes[start..]
+                .iter()
+                .position(|grapheme| grapheme.symbol.trim().is_empty() != is_space)
+                .map_or(graphemes.len(), |length| start + length);
+            let token = &graphemes[start..end];
+            let token_width: usize = token.iter().map(|grapheme| grapheme.symbol.width()).sum();
+            start = end;
+
+            if wrapped_width + token_width <= width {
+                wrapped.extend_from_slice(token);
+                wrapped_width += token_width;
+                continue;
+            }
+
+            // Whitespace at the end of a line is dropped, as is a word's break onto a new one
+            if !wrapped.is_empty() {
+                lines.push(styled_line(&wrapped, &line));
+                wrapped.clear();
+                wrapped_width = 0;
             }
+            if is_space {
+                continue;
+            }
+            for grapheme in token {
+                let grapheme_width = grapheme.symbol.width();
+                if wrapped_width + grapheme_width > width && !wrapped.is_empty() {
+                    lines.push(styled_line(&wrapped, &line));
+                    wrapped.clear();
+                    wrapped_width = 0;
+                }
+                wrapped.push(grapheme.clone());
+                wrapped_width += grapheme_width;
+            }
+        }
+        if !wrapped.is_empty() {
+            lines.push(styled_line(&wrapped, &line));
+        }
+    }
+
+    Text::from(lines)
+}
+
+/// Builds a line out of "graphemes", keeping the style and alignment of the line they were taken from
+fn styled_line(graphemes: &[StyledGrapheme], original: &Line) -> Line<'static> {
+    let mut spans: Vec<Span> = Vec::new();
+    for grapheme in graphemes {
+        match spans.last_mut() {
+            Some(span) if span.style == grapheme.style => span.content.to_mut().push_str(grapheme.symbol),
+            _ => spans.push(Span::styled(grapheme.symbol.to_string(), grapheme.style)),
         }
     }
+
+    let mut line = Line::from(spans).style(original.style);
+    line.alignment = original.alignment;
+    line
+}
+
+/// Splits a message body into lines, rendering the ones within ``` fences as code
+/// Code keeps its whitespace and is padded into a block of its own background
+fn format_body(body: &str) -> Text<'static> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut code_block: Vec<String> = Vec::new();
+    let mut in_code = false;
+
+    for line in body.lines() {
+        let trimmed = line.trim();
+
+        // A snippet fenced on both sides of a single line
+        if !in_code && trimmed.len() > 2 * CODE_FENCE.len() && trimmed.starts_with(CODE_FENCE) && trimmed.ends_with(CODE_FENCE) {
+            let code = &trimmed[CODE_FENCE.len()..trimmed.len() - CODE_FENCE.len()];
+            lines.extend(code_lines(&[code.to_string()]));
+            continue;
+        }
+
+        if trimmed.starts_with(CODE_FENCE) {
+            if in_code {
+                lines.extend(code_lines(&code_block));
+                code_block.clear();
+            }
+            in_code = !in_code;
+            continue;
+        }
+
+        if in_code {
+            code_block.push(line.replace('\t', &" ".repeat(TAB_WIDTH)));
+        } else {
+            lines.push(Line::from(line.to_string()));
+        }
+    }
+
+    // An unterminated block still reads as code
+    lines.extend(code_lines(&code_block));
+
+    Text::from(lines)
+}
+
+/// Styles "code" as a block, every line padded to the width of the longest one, as displayed
+fn code_lines(code: &[String]) -> Vec<Line<'static>> {
+    let width = code.iter().map(|line| line.width()).max().unwrap_or(0);
+    code
+        .iter()
+        .map(|line| {
+            let padding = " ".repeat(width - line.width());
+            Line::from(Span::styled(format!(" {line}{padding} "), CODE_STYLE))
+        })
+        .collect()
+}
+
+/// Sets the terminal window title, prefixed by the number of unread messages, if any
+fn set_window_title(unread: usize) {
+    let title = match unread {
+        0 => WINDOW_TITLE.to_string(),
+        n => format!("({n}) {WINDOW_TITLE}"),
+    };
+
+    if let Err(title_error) = execute!(std::io::stdout(), SetTitle(title)) {
+        log::debug!("Could not set the terminal title: {title_error}");
+    }
+}
+
+/// Captures the mouse, so that its wheel scrolls the chat, or gives it back to the terminal for selecting text
+/// Returns whether the terminal could be told
+fn set_mouse_capture(capture: bool) -> bool {
+    let result = if capture {
+        execute!(std::io::stdout(), EnableMouseCapture)
+    } else {
+        execute!(std::io::stdout(), DisableMouseCapture)
+    };
+    if let Err(mouse_error) = result {
+        log::warn!("Could not turn mouse capture {}: {mouse_error}", if capture {"on"} else {"off"});
+        return false;
+    }
+    true
+}
+
+/// Rings the terminal bell
+fn ring_bell() {
+    if let Err(bell_error) = execute!(std::io::stdout(), Print(BELL_CHAR)) {
+        log::debug!("Could not ring the terminal bell: {bell_error}");
+    }
+}
+
+/// Starts "command", i.e. ["paplay", "ding.wav"], without waiting for it
+/// Its output is discarded, as it would draw over the chat
+fn run_notify_command(command: &[String]) {
+    let Some((program, args)) = command.split_first() else {
+        return;
+    };
+    let spawned = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Err(spawn_error) = spawned {
+        log::warn!("Could not run NOTIFY_COMMAND \"{}\": {spawn_error}", command.join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unbroken_text_is_hard_wrapped() {
+        let blob = "a".repeat(2000);
+        let wrapped = wrap_text(Text::from(blob.clone()), 50);
+
+        assert_eq!(wrapped.lines.len(), 40);
+        assert!(wrapped.lines.iter().all(|line| line.width() == 50));
+        let rejoined: String = wrapped.lines.iter().flat_map(|line| line.spans.iter().map(|span| span.content.to_string())).collect();
+        assert_eq!(rejoined, blob);
+    }
+
+    #[test]
+    fn wide_text_is_wrapped_by_width() {
+        let wrapped = wrap_text(Text::from(format!("see {}", "日本語".repeat(100))), 21);
+        assert!(wrapped.lines.iter().all(|line| line.width() <= 21));
+    }
+
+    #[test]
+    fn input_is_wrapped_by_width() {
+        let mut input = InputBuffer::default();
+        input.set(&"日本語".repeat(10));
+        let lines = wrapped_input(&input, 20, 10, "_");
+
+        // 60 columns of text, and one more for the cursor
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(|line| line.width() <= 20));
+        assert_eq!(lines[3].to_string(), "_");
+    }
+
+    #[test]
+    fn one_message_on_a_three_row_terminal() {
+        // Inside the borders of the outer block, only a single row is left
+        let chat_area = Rect::new(0, 0, 80, 3).inner(Margin::new(1, 1));
+        let (msg_area, input_area) = split_input(chat_area, 1);
+        assert!(msg_area.height + input_area.height <= chat_area.height);
+
+        // A bubble has borders above and below, so none of it would make sense in a single row
+        assert!(stack_messages([3], msg_area).is_empty());
+        assert!(stack_messages([3], Rect::new(0, 0, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn messages_are_stacked_as_they_fit() {
+        let area = Rect::new(0, 2, 80, 10);
+        assert_eq!(stack_messages([4], area), [Rect::new(0, 8, 80, 4)]);
+        // Cut down to its borders, the topmost message is left out. With a row more, it is kept
+        assert_eq!(stack_messages([4, 4, 4], area), [Rect::new(0, 8, 80, 4), Rect::new(0, 4, 80, 4)]);
+        let area = Rect::new(0, 1, 80, 11);
+        assert_eq!(stack_messages([4, 4, 4], area), [Rect::new(0, 8, 80, 4), Rect::new(0, 4, 80, 4), Rect::new(0, 1, 80, 3)]);
+        assert_eq!(stack_messages([4, 5, 4], area), [Rect::new(0, 8, 80, 4), Rect::new(0, 3, 80, 5)]);
+    }
+
+    #[test]
+    fn code_blocks_are_padded_to_the_displayed_width() {
+        let code = ["let 名前 = 1;".to_string(), "x".to_string(), "🦀🦀".to_string()];
+        let widths: Vec<usize> = code_lines(&code).iter().map(|line| line.to_string().width()).collect();
+        assert_eq!(widths, [15, 15, 15]);
+    }
+
+    #[test]
+    fn input_keeps_line_breaks() {
+        let mut input = InputBuffer::default();
+        input.set("first\nsecond");
+        let lines: Vec<String> = wrapped_input(&input, 20, 10, "_").iter().map(Line::to_string).collect();
+        assert_eq!(lines, ["first", "second_"]);
+    }
 }
@@ -0,0 +1,73 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Canned replies posted by the bot whenever a message mentions    #
+//   one of the configured trigger words                             #
+//********************************************************************
+
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+// Constants
+pub const RULE_SEPARATOR: char = ';';      // Between the rules of AUTO_RESPONSES
+const TRIGGER_SEPARATOR: char = '=';       // Between the trigger and the response of a rule
+
+/// A reply posted whenever a message contains "trigger" as a whole word, ignoring case
+#[derive(Debug, PartialEq)]
+pub struct AutoResponse{
+    /// In lowercase
+    pub trigger: String,
+    pub response: String,
+}
+impl AutoResponse{
+    /// Parses a "trigger=response" rule, i.e. "rules=Be nice"
+    /// Returns None unless the trigger is a single word and the response isn't empty
+    pub fn parse(rule: &str) -> Option<Self>{
+        let (trigger, response) = rule.split_once(TRIGGER_SEPARATOR)?;
+        let (trigger, response) = (trigger.trim(), response.trim());
+        if !is_word(trigger) || response.is_empty() {
+            return None;
+        }
+        Some(Self{ trigger: trigger.to_lowercase(), response: response.to_string() })
+    }
+}
+
+/// Returns the first of "responses" whose trigger is a word of "body"
+/// Words are split like in "redact", so "hello!" triggers "hello" but "othello" doesn't
+pub fn find_response<'a>(body: &str, responses: &'a [AutoResponse]) -> Option<&'a AutoResponse> {
+    let words: Vec<String> = body
+        .split(|char: char| !char.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    responses.iter().find(|auto_response| words.contains(&auto_response.trigger))
+}
+
+/// Whether "trigger" is made of a single word, as nothing else could ever match
+fn is_word(trigger: &str) -> bool {
+    !trigger.is_empty() && trigger.chars().all(char::is_alphanumeric)
+}
+
+/// When each trigger was last answered in each room, so that the bot can't be made to flood a room
+#[derive(Default)]
+pub struct Cooldowns{
+    answered: Mutex<HashMap<(String, String), Instant>>,
+}
+impl Cooldowns{
+    /// Whether "trigger" may be answered in "room" now, at most once per "cooldown"
+    /// Answering is assumed to follow, starting the cooldown over
+    pub fn try_answer(&self, room: &str, trigger: &str, cooldown: Duration) -> bool{
+        let mut answered = self.answered.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        answered.retain(|_, at| at.elapsed() < cooldown);
+
+        let key = (room.to_string(), trigger.to_string());
+        if answered.contains_key(&key) {
+            return false;
+        }
+        answered.insert(key, Instant::now());
+        true
+    }
+}
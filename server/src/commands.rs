@@ -0,0 +1,577 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Slash commands sent by clients and handled by the server        #
+//********************************************************************
+
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use shared::{validate_color, validate_room_name, ChatMessage, Invite, MessageKind, Table, ROOM_READ_ONLY, ROOM_WRITABLE, SYSTEM_USERNAME};
+
+use crate::{
+    helpers::{
+        broadcast_message, broadcast_to_rooms, can_post, claim_username, current_room, enter_room, is_taken, joined_message, post_chat,
+        remember_last_seen, reset_alone, secrets_match, send_directory, send_room_access, send_to_peer, system_message, Audience, ServerState,
+    },
+    config::UsernameScope,
+    redaction::redact,
+};
+
+// Constants
+pub const COMMAND_PREFIX: &str = "/";
+const MAX_EPHEMERAL_SECS: u64 = 24 * 60 * 60;
+
+/// Parses and runs a command sent by the client at "client_addr"
+/// Any feedback is sent back to that client only
+pub async fn handle_command(input: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    let (command, args) = input.split_once(' ').unwrap_or((input, ""));
+    let args = args.trim();
+
+    log::debug!("{username} ({client_addr}) ran command {command}");
+
+    match command {
+        "/rename" => rename(args, client_addr, username, state).await,
+        "/join" => join(args, client_addr, username, state).await,
+        "/move" => move_client(args, client_addr, username, state).await,
+        "/admin" => admin(args, client_addr, username, state).await,
+        "/crosspost" => crosspost(args, client_addr, username, state).await,
+        "/readonly" => toggle_read_only(client_addr, username, state).await,
+        "/clearall" => clear_all(client_addr, username, state).await,
+        "/color" => pick_color(args, client_addr, username, state).await,
+        "/connections" => list_connections(client_addr, state).await,
+        "/disconnect" => disconnect(args, client_addr, username, state).await,
+        "/table" => post_table(args, client_addr, username, state).await,
+        "/ephemeral" => post_ephemeral(args, client_addr, username, state).await,
+        "/seen" => seen(args, client_addr, state).await,
+        "/invite" => invite(client_addr, username, state).await,
+        "/uptime" => uptime(client_addr, state).await,
+        _ => reply(format!("Unknown command {command}. /help lists them all"), client_addr, state).await,
+    }
+}
+
+/// Sends a SYSTEM message to the client at "client_addr" only
+async fn reply(body: String, client_addr: SocketAddr, state: &ServerState) {
+    let room = current_room(client_addr, &state.rooms).await;
+    match system_message(client_addr, &room, body) {
+        Some(message) => _ = send_to_peer(message, client_addr, &state.active_websockets).await,
+        None => log::error!("Could not create reply to {client_addr}"),
+    }
+}
+
+/// Changes the username of the client at "client_addr", if the new one is valid and free
+/// The client is told its new name, while everyone else is told of the change
+async fn rename(new_username: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    let ServerState{ active_websockets, rooms, .. } = state;
+    let room = current_room(client_addr, rooms).await;
+    if let Err(reason) = claim_username(client_addr, new_username, &room, state).await {
+        reply(format!("Could not rename: {reason}"), client_addr, state).await;
+        return;
+    }
+
+    log::info!("{username} ({client_addr}) is now {new_username}");
+    remember_last_seen(username, &state.last_seen).await;
+
+    // Tell the client its new identity
+    let confirmation = ChatMessage::builder()
+        .from(client_addr)
+        .username(SYSTEM_USERNAME.to_string())
+        .body(new_username.to_string())
+        .kind(MessageKind::Rename)
+        .build();
+    match confirmation {
+        Some(confirmation) => _ = send_to_peer(confirmation, client_addr, active_websockets).await,
+        None => log::error!("Could not create rename confirmation for {client_addr}"),
+    }
+
+    // Tell everyone else in the room
+    match system_message(client_addr, &room, format!("{username} is now {new_username}")) {
+        Some(notice) => _ = broadcast_message(notice, Audience::Others, state).await,
+        None => log::error!("Could not create rename broadcast message"),
+    }
+    send_directory(state).await;
+}
+
+/// Handles "/join", replying if the client at "client_addr" is already in "room" or can't be moved into it
+async fn join(room: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    if current_room(client_addr, &state.rooms).await == room {
+        reply(format!("You are already in #{room}"), client_addr, state).await;
+        return;
+    }
+    if let Err(reason) = move_to_room(room, client_addr, username, state).await {
+        reply(format!("Could not join: {reason}"), client_addr, state).await;
+    }
+}
+
+/// Moves the client given by "args", formatted as "<address or username> <room>", into that room. Admins only
+/// The client is told who moved it, and its view switches over as if it had joined by itself
+async fn move_client(args: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    if !state.admins.lock().await.contains(&client_addr) {
+        reply("Only admins may move clients".to_string(), client_addr, state).await;
+        return;
+    }
+    let Some((target, room)) = args.split_once(' ').map(|(target, room)| (target, room.trim())) else {
+        reply("Usage: /move <address or username> <room>".to_string(), client_addr, state).await;
+        return;
+    };
+
+    let target_addr = match target.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(_) => find_client(target, client_addr, state).await,
+    };
+    let target_addr = match target_addr {
+        Ok(target_addr) => target_addr,
+        Err(reason) => {
+            reply(reason, client_addr, state).await;
+            return;
+        }
+    };
+    let Some(target) = state.con_to_username.lock().await.get(&target_addr).cloned() else {
+        reply(format!("Nobody is logged in from {target}"), client_addr, state).await;
+        return;
+    };
+    let target = target.as_str();
+
+    if let Err(reason) = move_to_room(room, target_addr, target, state).await {
+        reply(format!("Could not move {target}: {reason}"), client_addr, state).await;
+        return;
+    }
+    log::info!("{username} ({client_addr}) moved {target} ({target_addr}) to #{room}");
+    reply(format!("You were moved to #{room} by {username}"), target_addr, state).await;
+    reply(format!("Moved {target} to #{room}"), client_addr, state).await;
+}
+
+/// Returns the address of the client called "target"
+/// Usernames repeating across rooms resolve to the one in the room of the client at "client_addr", if any,
+/// as that is the one it sees. Otherwise the address is needed to tell them apart
+async fn find_client(target: &str, client_addr: SocketAddr, state: &ServerState) -> Result<SocketAddr, String> {
+    let rooms = state.rooms.lock().await;
+    let candidates: Vec<SocketAddr> = state.con_to_username
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, name)| name.as_str() == target)
+        .map(|(addr, _)| *addr)
+        .collect();
+
+    let own_room = rooms.get(&client_addr);
+    match candidates.as_slice() {
+        [] => Err(format!("Nobody is called {target}")),
+        [addr] => Ok(*addr),
+        _ => candidates
+            .iter()
+            .find(|addr| own_room.is_some() && rooms.get(*addr) == own_room)
+            .copied()
+            .ok_or(format!("Several users are called {target}, give the address of the one you mean, as listed by /connections")),
+    }
+}
+
+/// Moves the client at "client_addr" into "room", letting both the old and the new rooms know
+/// Returns the reason if the room is invalid, is the one the client is in already, or can't be created
+async fn move_to_room(room: &str, client_addr: SocketAddr, username: &str, state: &ServerState) -> Result<(), String> {
+    let ServerState{ active_websockets, rooms, .. } = state;
+    validate_room_name(room)?;
+
+    let old_room = current_room(client_addr, rooms).await;
+    if old_room == room {
+        return Err(format!("{username} is already in #{room}"));
+    }
+
+    // Usernames scoped to rooms must stay unique in the room entered as well
+    if state.config().username_scope == UsernameScope::Room {
+        let rooms = rooms.lock().await;
+        if is_taken(username, client_addr, room, UsernameScope::Room, &*state.con_to_username.lock().await, &rooms) {
+            return Err(format!("someone called {username} is already in #{room}"));
+        }
+    }
+
+    enter_room(client_addr, room, rooms, &state.config()).await?;
+    reset_alone(room, state).await;
+
+    match system_message(client_addr, &old_room, format!("{username} has left the room")) {
+        Some(notice) => _ = broadcast_message(notice, Audience::Others, state).await,
+        None => log::error!("Could not create room exit broadcast message"),
+    }
+
+    log::info!("{username} ({client_addr}) moved from #{old_room} to #{room}");
+
+    // Tell the client where it is now
+    match joined_message(client_addr, room) {
+        Some(confirmation) => _ = send_to_peer(confirmation, client_addr, active_websockets).await,
+        None => log::error!("Could not create join confirmation for {client_addr}"),
+    }
+    if !can_post(client_addr, room, state).await {
+        send_room_access(client_addr, room, state).await;
+    }
+
+    match system_message(client_addr, room, format!("{username} has entered the room")) {
+        Some(notice) => _ = broadcast_message(notice, Audience::Others, state).await,
+        None => log::error!("Could not create room entry broadcast message"),
+    }
+    send_directory(state).await;
+    Ok(())
+}
+
+/// Grants the client at "client_addr" admin rights, if "password" matches the configured one
+async fn admin(password: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    let Some(admin_password) = &state.config().admin_password else {
+        reply("Admin rights are disabled on this server".to_string(), client_addr, state).await;
+        return;
+    };
+
+    if !secrets_match(password, admin_password) {
+        log::warn!("{username} ({client_addr}) failed to authenticate as admin");
+        reply("Wrong admin password".to_string(), client_addr, state).await;
+        return;
+    }
+
+    state.admins.lock().await.insert(client_addr);
+    log::info!("{username} ({client_addr}) is now an admin");
+    reply("You are now an admin".to_string(), client_addr, state).await;
+
+    // Admins may post anywhere
+    let room = current_room(client_addr, &state.rooms).await;
+    if state.read_only_rooms.lock().await.contains(&room) {
+        send_room_access(client_addr, &room, state).await;
+    }
+}
+
+/// Sends "args", formatted as "room1,room2 <text>", into each of the listed rooms at once. Admins only
+/// Invalid room names are skipped, and the client is told about them
+async fn crosspost(args: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    if !state.admins.lock().await.contains(&client_addr) {
+        reply("Only admins may cross-post".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let (room_list, body) = args.split_once(' ').unwrap_or((args, ""));
+    let body = body.trim();
+    if body.is_empty() {
+        reply("Usage: /crosspost room1,room2 <message>".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let mut target_rooms = HashSet::new();
+    for room in room_list.split(',').map(str::trim).filter(|room| !room.is_empty()) {
+        match validate_room_name(room) {
+            Ok(()) => _ = target_rooms.insert(room.to_string()),
+            Err(reason) => reply(format!("Skipping \"{room}\": {reason}"), client_addr, state).await,
+        }
+    }
+    if target_rooms.is_empty() {
+        reply("Could not cross-post: no valid rooms given".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let message = ChatMessage::builder()
+        .from(client_addr)
+        .username(username.to_string())
+        .body(redact(body, &state.config().redacted_words))
+        .room(current_room(client_addr, &state.rooms).await)
+        .build();
+    let Some(message) = message else {
+        log::error!("Could not create cross-post message from {client_addr}");
+        return;
+    };
+
+    let recipients = broadcast_to_rooms(message, &target_rooms, Audience::Others, state).await;
+
+    let mut room_names: Vec<String> = target_rooms.iter().map(|room| format!("#{room}")).collect();
+    room_names.sort();
+    log::info!("{username} ({client_addr}) cross-posted to {}", room_names.join(", "));
+    reply(format!("Cross-posted to {recipients} member(s) of {}", room_names.join(", ")), client_addr, state).await;
+}
+
+/// Makes the current room of the client at "client_addr" read-only, or writable again if it already was. Admins only
+/// Everyone else in the room who isn't an admin is told whether they may still post
+async fn toggle_read_only(client_addr: SocketAddr, username: &str, state: &ServerState) {
+    if !state.admins.lock().await.contains(&client_addr) {
+        reply("Only admins may change whether a room is read-only".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let room = current_room(client_addr, &state.rooms).await;
+    let read_only = {
+        let mut read_only_rooms = state.read_only_rooms.lock().await;
+        if !read_only_rooms.remove(&room) {
+            read_only_rooms.insert(room.clone());
+        }
+        read_only_rooms.contains(&room)
+    };
+
+    let access = if read_only {ROOM_READ_ONLY} else {ROOM_WRITABLE};
+    log::info!("{username} ({client_addr}) made #{room} {access}");
+    reply(format!("#{room} is now {access}"), client_addr, state).await;
+
+    let members: Vec<SocketAddr> = state
+        .rooms
+        .lock()
+        .await
+        .iter()
+        .filter(|(addr, member_room)| **member_room == room && **addr != client_addr)
+        .map(|(addr, _)| *addr)
+        .collect();
+    for member in members {
+        if !state.admins.lock().await.contains(&member) {
+            send_room_access(member, &room, state).await;
+        }
+    }
+}
+
+/// Tells everyone in the current room of the client at "client_addr" to clear their view of it. Admins only
+/// The server keeps no history itself, so there is nothing to wipe on this end
+async fn clear_all(client_addr: SocketAddr, username: &str, state: &ServerState) {
+    if !state.admins.lock().await.contains(&client_addr) {
+        reply("Only admins may clear the history".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let room = current_room(client_addr, &state.rooms).await;
+    let directive = ChatMessage::builder()
+        .from(client_addr)
+        .username(SYSTEM_USERNAME.to_string())
+        .body(room.clone())
+        .kind(MessageKind::ClearHistory)
+        .room(room.clone())
+        .build();
+    let Some(directive) = directive else {
+        log::error!("Could not create clear directive for #{room}");
+        return;
+    };
+
+    // Peers are sent messages in order, so anything broadcast from now on is kept
+    broadcast_message(directive, Audience::Everyone, state).await;
+
+    log::info!("{username} ({client_addr}) cleared the history of #{room}");
+    match system_message(client_addr, &room, format!("The history of #{room} was cleared by {username}")) {
+        Some(notice) => _ = broadcast_message(notice, Audience::Everyone, state).await,
+        None => log::error!("Could not create clear broadcast message"),
+    }
+}
+
+/// Makes everyone render the client at "client_addr" in "color", or in its default color again if empty
+async fn pick_color(color: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    if color.is_empty() {
+        state.colors.lock().await.remove(&client_addr);
+        reply("Your color is back to the default".to_string(), client_addr, state).await;
+    } else {
+        let color = match validate_color(color) {
+            Ok(color) => color,
+            Err(reason) => {
+                reply(format!("Could not change color: {reason}"), client_addr, state).await;
+                return;
+            }
+        };
+        log::info!("{username} ({client_addr}) picked the color {color}");
+        state.colors.lock().await.insert(client_addr, color.clone());
+        reply(format!("Your color is now {color}"), client_addr, state).await;
+    }
+
+    send_directory(state).await;
+}
+
+/// Lists every active connection, with its username, room and how long it has been connected. Admins only
+async fn list_connections(client_addr: SocketAddr, state: &ServerState) {
+    if !state.admins.lock().await.contains(&client_addr) {
+        reply("Only admins may list connections".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let mut connections: Vec<(SocketAddr, Duration)> = state.active_websockets
+        .lock()
+        .await
+        .iter()
+        .map(|(addr, tx)| (*addr, tx.connected_for()))
+        .collect();
+    connections.sort_by_key(|(_, connected_for)| std::cmp::Reverse(*connected_for));
+
+    let usernames = state.con_to_username.lock().await.clone();
+    let rooms = state.rooms.lock().await.clone();
+    let mut listing = format!("{} connection(s):", connections.len());
+    for (addr, connected_for) in connections {
+        // Clients still picking a username have neither a name nor a room yet
+        let username = usernames.get(&addr).map_or("(no username yet)", String::as_str);
+        let room = rooms.get(&addr).map_or(String::new(), |room| format!(" in #{room}"));
+        listing += &format!("\n{addr} {username}{room}, connected for {}", format_duration(connected_for));
+    }
+    reply(listing, client_addr, state).await;
+}
+
+/// Closes the connection of the client given by "target", either its address or its username. Admins only
+async fn disconnect(target: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    if !state.admins.lock().await.contains(&client_addr) {
+        reply("Only admins may disconnect clients".to_string(), client_addr, state).await;
+        return;
+    }
+    if target.is_empty() {
+        reply("Usage: /disconnect <address or username>".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let target_addr = match target.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => match find_client(target, client_addr, state).await {
+            Ok(addr) => addr,
+            Err(reason) => {
+                reply(reason, client_addr, state).await;
+                return;
+            }
+        },
+    };
+    let Some(tx) = state.active_websockets.lock().await.get(&target_addr).cloned() else {
+        reply(format!("No connection matches {target}"), client_addr, state).await;
+        return;
+    };
+
+    log::info!("{username} ({client_addr}) disconnected {target_addr}");
+    tx.disconnect();
+    reply(format!("Disconnected {target_addr}"), client_addr, state).await;
+}
+
+/// Posts the table in "json" to the current room of the client at "client_addr", including the client itself
+/// Cells are redacted like any chat message
+async fn post_table(json: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    let room = current_room(client_addr, &state.rooms).await;
+    if !can_post(client_addr, &room, state).await {
+        reply(format!("Only admins may post in #{room}"), client_addr, state).await;
+        return;
+    }
+
+    let mut table = match Table::parse(json) {
+        Ok(table) => table,
+        Err(reason) => {
+            reply(format!("Could not post table: {reason}"), client_addr, state).await;
+            return;
+        }
+    };
+    for cell in table.headers.iter_mut().chain(table.rows.iter_mut().flatten()) {
+        *cell = redact(cell, &state.config().redacted_words);
+    }
+
+    let body = match serde_json::to_string(&table) {
+        Ok(body) => body,
+        Err(serialize_error) => {
+            log::error!("Could not serialize table from {client_addr}: {serialize_error}");
+            return;
+        }
+    };
+    let message = ChatMessage::builder()
+        .from(client_addr)
+        .username(username.to_string())
+        .body(body)
+        .kind(MessageKind::Table)
+        .room(room)
+        .build();
+    let Some(message) = message else {
+        log::error!("Could not create table message from {client_addr}");
+        return;
+    };
+
+    // Unlike chat, clients don't show their own tables until the server echoes them back
+    broadcast_message(message, Audience::Everyone, state).await;
+}
+
+/// Sends "args", formatted as "<seconds> <text>", as a chat message which disappears that many seconds after
+/// being received
+async fn post_ephemeral(args: &str, client_addr: SocketAddr, username: &str, state: &ServerState) {
+    let usage = format!("Usage: /ephemeral <seconds, up to {MAX_EPHEMERAL_SECS}> <message>");
+    let Some((secs, text)) = args.split_once(' ') else {
+        reply(usage, client_addr, state).await;
+        return;
+    };
+    let (secs, text) = match secs.parse::<u64>() {
+        Ok(secs) if (1..=MAX_EPHEMERAL_SECS).contains(&secs) && !text.trim().is_empty() => (secs, text.trim()),
+        _ => {
+            reply(usage, client_addr, state).await;
+            return;
+        }
+    };
+
+    post_chat(text.to_string(), client_addr, username, Some(Duration::from_secs(secs)), state).await;
+}
+
+/// Tells the client at "client_addr" when "username" was last connected
+async fn seen(username: &str, client_addr: SocketAddr, state: &ServerState) {
+    if username.is_empty() {
+        reply("Usage: /seen <username>".to_string(), client_addr, state).await;
+        return;
+    }
+
+    let online = state.con_to_username.lock().await.values().any(|name| name == username);
+    let body = if online {
+        format!("{username} is online now")
+    } else {
+        match state.last_seen.lock().await.get(username) {
+            Some(at) => format!("{username} was last seen {} ago", format_duration(at.elapsed())),
+            None => format!("{username} has not been seen since the server started"),
+        }
+    };
+    reply(body, client_addr, state).await;
+}
+
+/// Tells the client at "client_addr" how long the server has been up, and how busy it is
+/// Only what the server already keeps track of is reported, so this stays cheap
+async fn uptime(client_addr: SocketAddr, state: &ServerState) {
+    let (connections, queued) = {
+        let actives = state.active_websockets.lock().await;
+        (actives.len(), actives.values().map(|tx| tx.queued()).sum::<usize>())
+    };
+    let room_count = state.rooms.lock().await.values().collect::<HashSet<_>>().len();
+
+    let body = format!(
+        "Up for {}\n{connections} connection(s) in {room_count} room(s)\n{queued} message(s) waiting to be sent",
+        format_duration(state.started_at.elapsed()),
+    );
+    reply(body, client_addr, state).await;
+}
+
+/// Sends the client at "client_addr" what it needs to invite someone into its room
+/// If the server requires a token, a one-time one is included
+async fn invite(client_addr: SocketAddr, username: &str, state: &ServerState) {
+    let room = current_room(client_addr, &state.rooms).await;
+    let token = match state.config().auth_token {
+        Some(_) => match state.invites.issue() {
+            Some(token) => Some(token),
+            None => {
+                reply("Too many invites are pending, try again later".to_string(), client_addr, state).await;
+                return;
+            }
+        },
+        None => None,
+    };
+    log::info!("{username} ({client_addr}) invited someone into #{room}");
+
+    let invite = Invite{ server: String::new(), room: room.clone(), token };
+    let body = match serde_json::to_string(&invite) {
+        Ok(body) => body,
+        Err(serialize_error) => {
+            log::error!("Could not serialize invite for {client_addr}: {serialize_error}");
+            return;
+        }
+    };
+    let message = ChatMessage::builder()
+        .from(client_addr)
+        .username(SYSTEM_USERNAME.to_string())
+        .body(body)
+        .kind(MessageKind::Invite)
+        .room(room)
+        .build();
+    match message {
+        Some(message) => _ = send_to_peer(message, client_addr, &state.active_websockets).await,
+        None => log::error!("Could not create invite for {client_addr}"),
+    }
+}
+
+/// Formats "duration" as i.e. "1h 2m 3s", leaving out the leading units which are zero
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{secs}s"),
+        (0, _) => format!("{minutes}m {secs}s"),
+        _ => format!("{hours}h {minutes}m {secs}s"),
+    }
+}
@@ -0,0 +1,286 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   The server configuration, read from an optional CONFIG_FILE     #
+//   and environment variables                                       #
+//********************************************************************
+
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use shared::{validate_room_name, validate_username, DEFAULT_ROOM};
+
+use crate::autoresponses::{AutoResponse, RULE_SEPARATOR};
+
+// Constants
+const DEFAULT_PORT: u16 = 5050;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 5;
+const DEFAULT_STATS_INTERVAL_SECS: u64 = 60;
+const DEFAULT_QUEUE_WARN_THRESHOLD: usize = 100;
+const DEFAULT_MAX_ROOMS: usize = 100;
+const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+const DEFAULT_KEEPALIVE_IDLE_SECS: u64 = 60;
+const DEFAULT_KEEPALIVE_INTERVAL_SECS: u64 = 10;
+const DEFAULT_BOT_NAME: &str = "bot";
+const DEFAULT_AUTO_RESPONSE_COOLDOWN_SECS: u64 = 30;
+
+/// What is done with messages over the length limit
+#[derive(PartialEq)]
+pub enum LongMessages {
+    /// The message is dropped and the sender told why
+    Reject,
+    /// The message is cut at the limit, ending with an ellipsis, and the sender told so
+    Truncate,
+}
+impl FromStr for LongMessages {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(Self::Reject),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where usernames must be unique
+#[derive(PartialEq, Clone, Copy)]
+pub enum UsernameScope {
+    /// Nobody else on the server may have the same username
+    Server,
+    /// Nobody else in the same room may, so there may be an "alice" in every room
+    /// Commands naming a user pick the one in the caller's room, and otherwise want the address if several share the name
+    Room,
+}
+impl FromStr for UsernameScope {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "server" => Ok(Self::Server),
+            "room" => Ok(Self::Room),
+            _ => Err(()),
+        }
+    }
+}
+
+/// All server settings
+#[derive(PartialEq)]
+pub struct Config {
+    /// Which port connections are accepted on
+    pub port: u16,
+    /// How long connections are given to close on shutdown, before being dropped
+    pub shutdown_grace: Duration,
+    /// How often the connection stats are logged
+    pub stats_interval: Duration,
+    /// How many messages may be queued for a single peer before warning about it
+    pub queue_warn_threshold: usize,
+    /// How many clients may be connected at once. Any more are told to try again later
+    pub max_connections: usize,
+    /// How many clients may be connected at once from the same IP address, whatever the port. No limit if None
+    pub max_connections_per_ip: Option<usize>,
+    /// How many rooms may exist at once, permanent ones included
+    pub max_rooms: usize,
+    /// Rooms which always exist, even when empty
+    pub permanent_rooms: Vec<String>,
+    /// The password granting admin rights through "/admin". Nobody can become an admin if unset
+    pub admin_password: Option<String>,
+    /// Words replaced with asterisks in every message, in lowercase. Nothing is redacted if empty
+    pub redacted_words: Vec<String>,
+    /// Rooms in which only admins may post, to begin with. Admins may change them with "/readonly"
+    pub read_only_rooms: Vec<String>,
+    /// Whether every broadcast message is also printed to stdout
+    pub echo_messages: bool,
+    /// How long a connection may be idle before TCP keepalive probes are sent
+    pub keepalive_idle: Duration,
+    /// How often TCP keepalive probes are repeated while unanswered
+    pub keepalive_interval: Duration,
+    /// The token clients must send as "Authorization: Bearer <token>" when connecting. Anyone may connect if unset
+    pub auth_token: Option<String>,
+    /// How many characters a message may be made of. Only the websocket message size is limited if None
+    pub max_message_length: Option<usize>,
+    /// What is done with messages longer than "max_message_length"
+    pub long_messages: LongMessages,
+    /// How long the departure of a client is held back, in case it comes right back. Announced at once if zero
+    pub flap_window: Duration,
+    /// How far taken usernames are suffixed to find a free one, i.e. up to "alice9" for 9. Taken ones are turned down if None
+    pub max_username_suffix: Option<u32>,
+    /// Where usernames must be unique. Only enforced on new usernames and when moving rooms, so clients already
+    /// sharing a name keep it if this is narrowed on reload
+    pub username_scope: UsernameScope,
+    /// Replies posted by the bot to messages mentioning their trigger. The bot stays quiet if empty
+    pub auto_responses: Vec<AutoResponse>,
+    /// The username auto-responses are posted under
+    pub bot_name: String,
+    /// How long the bot waits before answering the same trigger in the same room again
+    pub auto_response_cooldown: Duration,
+}
+impl Config {
+    /// Reads the configuration from CONFIG_FILE, if set, and environment variables for anything it leaves out,
+    /// falling back to defaults
+    /// Invalid values are left out as if unset, and returned along with the configuration
+    /// Returns an error if CONFIG_FILE can't be read
+    pub fn load() -> Result<(Self, Vec<String>), String> {
+        let mut source = Source::open()?;
+        let config = Self {
+            port: source.parse_or("PORT", DEFAULT_PORT),
+            shutdown_grace: Duration::from_secs(source.parse_or("SHUTDOWN_GRACE_SECS", DEFAULT_SHUTDOWN_GRACE_SECS)),
+            stats_interval: Duration::from_secs(source.parse_or("STATS_INTERVAL_SECS", DEFAULT_STATS_INTERVAL_SECS).max(1)),
+            queue_warn_threshold: source.parse_or("QUEUE_WARN_THRESHOLD", DEFAULT_QUEUE_WARN_THRESHOLD),
+            max_connections: source.parse_or("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS),
+            max_connections_per_ip: Some(source.parse_or("MAX_CONNECTIONS_PER_IP", 0)).filter(|max| *max > 0),
+            max_rooms: source.parse_or("MAX_ROOMS", DEFAULT_MAX_ROOMS),
+            permanent_rooms: source.rooms("PERMANENT_ROOMS").unwrap_or(vec![DEFAULT_ROOM.to_string()]),
+            read_only_rooms: source.rooms("READ_ONLY_ROOMS").unwrap_or_default(),
+            echo_messages: source.parse_or("ECHO_MESSAGES", false),
+            keepalive_idle: Duration::from_secs(source.parse_or("KEEPALIVE_IDLE_SECS", DEFAULT_KEEPALIVE_IDLE_SECS).max(1)),
+            keepalive_interval: Duration::from_secs(source.parse_or("KEEPALIVE_INTERVAL_SECS", DEFAULT_KEEPALIVE_INTERVAL_SECS).max(1)),
+            admin_password: source.var("ADMIN_PASSWORD").filter(|password| !password.is_empty()),
+            auth_token: source.var("AUTH_TOKEN").filter(|token| !token.is_empty()),
+            max_message_length: Some(source.parse_or("MAX_MESSAGE_LENGTH", 0)).filter(|length| *length > 0),
+            long_messages: source.parse_or("LONG_MESSAGES", LongMessages::Reject),
+            flap_window: Duration::from_secs(source.parse_or("FLAP_WINDOW_SECS", 0)),
+            max_username_suffix: Some(source.parse_or("MAX_USERNAME_SUFFIX", 0)).filter(|suffix| *suffix > 1),
+            username_scope: source.parse_or("USERNAME_SCOPE", UsernameScope::Server),
+            redacted_words: source.words("REDACTED_WORDS"),
+            auto_responses: source.auto_responses("AUTO_RESPONSES"),
+            bot_name: source.username("BOT_NAME").unwrap_or(DEFAULT_BOT_NAME.to_string()),
+            auto_response_cooldown: Duration::from_secs(source.parse_or("AUTO_RESPONSE_COOLDOWN_SECS", DEFAULT_AUTO_RESPONSE_COOLDOWN_SECS)),
+        };
+        Ok((config, source.problems))
+    }
+
+    /// Names the settings which differ from those in "other"
+    pub fn changes(&self, other: &Self) -> Vec<&'static str> {
+        macro_rules! changed {
+            ($($field:ident),* $(,)?) => {
+                [$((stringify!($field), self.$field != other.$field)),*]
+            };
+        }
+
+        changed!(
+            port, shutdown_grace, stats_interval, queue_warn_threshold, max_connections, max_connections_per_ip, max_rooms, permanent_rooms,
+            admin_password, redacted_words, read_only_rooms, echo_messages, keepalive_idle, keepalive_interval, auth_token,
+            max_message_length, long_messages, flap_window, max_username_suffix, username_scope, auto_responses, bot_name, auto_response_cooldown,
+        )
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// Whether "room" is one of the rooms which are never deleted
+    pub fn is_permanent_room(&self, room: &str) -> bool {
+        self.permanent_rooms.iter().any(|permanent| permanent == room)
+    }
+}
+
+/// Where settings are read from: CONFIG_FILE if set, then the environment
+/// Problems with individual values are noted, rather than failing the whole configuration
+struct Source {
+    file: HashMap<String, String>,
+    problems: Vec<String>,
+}
+impl Source {
+    /// Reads CONFIG_FILE, if set, made of "NAME=value" lines named like the environment variables
+    /// Blank lines and lines starting with '#' are skipped, and values may be wrapped in double quotes
+    fn open() -> Result<Self, String> {
+        let mut file = HashMap::new();
+        if let Ok(path) = std::env::var("CONFIG_FILE") {
+            let contents = std::fs::read_to_string(&path).map_err(|read_error| format!("could not read {path}: {read_error}"))?;
+            for (index, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (name, value) = line.split_once('=').ok_or(format!("line {} of {path} is not NAME=value", index + 1))?;
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+                file.insert(name.trim().to_string(), value.to_string());
+            }
+        }
+
+        Ok(Self{ file, problems: Vec::new() })
+    }
+
+    /// The value of the setting "name", if set
+    fn var(&self, name: &str) -> Option<String> {
+        self.file.get(name).cloned().or_else(|| std::env::var(name).ok())
+    }
+
+    /// Parses the setting "name", if set
+    /// Invalid values are noted and replaced by "default"
+    fn parse_or<T: FromStr>(&mut self, name: &str, default: T) -> T {
+        match self.var(name) {
+            Some(value) => value.parse().unwrap_or_else(|_| {
+                self.problems.push(format!("Invalid value \"{value}\" for {name}"));
+                default
+            }),
+            None => default,
+        }
+    }
+
+    /// Reads a comma separated list of rooms from the setting "name", if set, skipping invalid names
+    fn rooms(&mut self, name: &str) -> Option<Vec<String>> {
+        let value = self.var(name)?;
+
+        let mut rooms = Vec::new();
+        for room in value.split(',').map(str::trim).filter(|room| !room.is_empty()) {
+            match validate_room_name(room) {
+                Ok(()) => rooms.push(room.to_string()),
+                Err(reason) => self.problems.push(format!("Invalid room \"{room}\" in {name}: {reason}")),
+            }
+        }
+        Some(rooms)
+    }
+
+    /// Reads a comma separated list of words from the setting "name", in lowercase, skipping any which can't be
+    /// matched as a whole word, i.e. with spaces or punctuation in it
+    fn words(&mut self, name: &str) -> Vec<String> {
+        let Some(value) = self.var(name) else {
+            return Vec::new();
+        };
+
+        let mut words = Vec::new();
+        for word in value.split(',').map(str::trim).filter(|word| !word.is_empty()) {
+            if word.chars().all(char::is_alphanumeric) {
+                words.push(word.to_lowercase());
+            } else {
+                self.problems.push(format!("Invalid word \"{word}\" in {name}: only letters and digits can make up a word"));
+            }
+        }
+        words
+    }
+
+    /// Reads the "trigger=response" rules separated by ';' from the setting "name", skipping invalid ones
+    fn auto_responses(&mut self, name: &str) -> Vec<AutoResponse> {
+        let Some(value) = self.var(name) else {
+            return Vec::new();
+        };
+
+        let mut auto_responses = Vec::new();
+        for rule in value.split(RULE_SEPARATOR).map(str::trim).filter(|rule| !rule.is_empty()) {
+            match AutoResponse::parse(rule) {
+                Some(auto_response) => auto_responses.push(auto_response),
+                None => self.problems.push(format!("Invalid rule \"{rule}\" in {name}: expected a single word, '=' and a response")),
+            }
+        }
+        auto_responses
+    }
+
+    /// Reads a username from the setting "name", if set and valid
+    fn username(&mut self, name: &str) -> Option<String> {
+        let username = self.var(name)?;
+        match validate_username(&username) {
+            Ok(()) => Some(username),
+            Err(reason) => {
+                self.problems.push(format!("Invalid username \"{username}\" for {name}: {reason}"));
+                None
+            }
+        }
+    }
+}
@@ -6,63 +6,409 @@
 // Date: 2025                                                        #
 //********************************************************************
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::{Duration, Instant}};
 
 use futures_util::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
-use shared::{ChatMessage, ClientMessage, HandleError, HandleResult};
-use tokio::{net::TcpStream, sync::{mpsc::{UnboundedReceiver, UnboundedSender}, Mutex}};
-use tokio_tungstenite::{tungstenite::{Error, Message}, WebSocketStream};
+use shared::{
+    validate_username, ChatMessage, ClientMessage, HandleError, HandleResult, MessageKind, DEFAULT_ROOM, MAX_USERNAME_LENGTH, ROOM_READ_ONLY,
+    ROOM_WRITABLE, SYSTEM_USERNAME,
+};
+use tokio::{net::TcpStream, select, sync::{mpsc::{error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender}, Mutex, Notify}, time::timeout};
+use tokio_tungstenite::{tungstenite::{protocol::CloseFrame, Error, Message}, WebSocketStream};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{autoresponses::find_response, commands::{handle_command, COMMAND_PREFIX}, config::{Config, LongMessages, UsernameScope}, redaction::redact};
 
-pub type Tx = UnboundedSender<ChatMessage>;
 pub type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
 pub type UsernameMap = Arc<Mutex<HashMap<SocketAddr, String>>>;
+pub type RoomMap = Arc<Mutex<HashMap<SocketAddr, String>>>;
+pub type AdminSet = Arc<Mutex<HashSet<SocketAddr>>>;
+pub type RoomSet = Arc<Mutex<HashSet<String>>>;
+pub type DepartureMap = Arc<Mutex<HashMap<String, Departure>>>;
+pub type ColorMap = Arc<Mutex<HashMap<SocketAddr, String>>>;
+pub type LastSeenMap = Arc<Mutex<HashMap<String, Instant>>>;
+pub type ActivityMap = Arc<Mutex<HashMap<String, u64>>>;
+pub type LonelySet = Arc<Mutex<HashSet<SocketAddr>>>;
+
+// Constants
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);   // A peer which doesn't accept a message by then is considered gone
+const ELLIPSIS: char = '…';
+const STARTUP_ONLY_SETTINGS: [&str; 3] = ["port", "stats_interval", "read_only_rooms"];  // Only read once, so reloading them has no effect
+const MAX_LAST_SEEN: usize = 10_000;                      // Usernames remembered by "/seen", the oldest being forgotten first
+const ACTIVITY_INTERVAL: Duration = Duration::from_secs(2);  // Room activity is sent at most this often, rather than on every message
+
+/// Everything the connection tasks share
+pub struct ServerState{
+    pub active_websockets: PeerMap,
+    pub con_to_username: UsernameMap,
+    pub rooms: RoomMap,
+    /// Clients which have authenticated with "/admin"
+    pub admins: AdminSet,
+    /// Rooms in which only admins may post
+    pub read_only_rooms: RoomSet,
+    /// Departures not announced yet, by username
+    pub departures: DepartureMap,
+    /// The colors clients picked with "/color"
+    pub colors: ColorMap,
+    /// When each username was last connected, for "/seen"
+    pub last_seen: LastSeenMap,
+    /// How many messages were posted in each room, for the clients' unread counts
+    pub activity: ActivityMap,
+    /// Clients told they are the only one in their room, which aren't told again until someone else enters it
+    pub told_alone: LonelySet,
+    /// One-time tokens handed out by "/invite"
+    pub invites: crate::invites::Invites,
+    /// When the server started, for "/uptime"
+    pub started_at: Instant,
+    /// When the bot last answered each trigger in each room
+    pub auto_response_cooldowns: crate::autoresponses::Cooldowns,
+    #[cfg(feature = "latency-stats")]
+    pub latencies: crate::latency::Latencies,
+    /// Read through "config()", as it may be reloaded at any time
+    pub config: std::sync::RwLock<Arc<Config>>,
+}
+impl ServerState{
+    /// The current configuration
+    /// Settings which are used together should be read from the same one, so a reload can't come in between
+    pub fn config(&self) -> Arc<Config>{
+        Arc::clone(&self.config.read().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    /// Swaps in "config" for anything which reads it from now on
+    pub fn set_config(&self, config: Config){
+        *self.config.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(config);
+    }
+}
+
+/// Notes that "username" has just gone away, forgetting the longest gone username if there are too many
+pub async fn remember_last_seen(username: &str, last_seen: &LastSeenMap) {
+    let mut last_seen = last_seen.lock().await;
+    last_seen.insert(username.to_string(), Instant::now());
+    if last_seen.len() > MAX_LAST_SEEN {
+        let oldest = last_seen.iter().min_by_key(|(_, at)| **at).map(|(username, _)| username.clone());
+        if let Some(oldest) = oldest {
+            last_seen.remove(&oldest);
+        }
+    }
+}
+
+/// A client which just left, whose departure is held back for a while in case it comes right back
+pub struct Departure{
+    room: String,
+    at: Instant,
+}
+
+/// The sending half of a peer's message queue
+/// Keeps count of the messages which the peer's task has not picked up yet
+/// and lets the peer be told to disconnect
+#[derive(Clone)]
+pub struct Tx{
+    sender: UnboundedSender<ChatMessage>,
+    queued: Arc<AtomicUsize>,
+    peer: SocketAddr,
+    warn_threshold: usize,
+    connected_at: Instant,
+    disconnect: Arc<Notify>,
+}
+impl Tx{
+    /// Queues a message for the peer, warning if the peer is falling behind
+    /// Fails if the peer's task is gone
+    pub fn send(&self, message: ChatMessage) -> Result<(), SendError<()>>{
+        // Counted before it can be received, so that "Rx::recv" never takes it off first
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.sender.send(message).is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(SendError(()));
+        }
+
+        if queued == self.warn_threshold {
+            log::warn!("Peer {} has {queued} queued messages. It may be a slow consumer", self.peer);
+        }
+        Ok(())
+    }
+
+    /// The number of messages waiting to be sent to the peer
+    pub fn queued(&self) -> usize{
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// How long ago the peer connected
+    pub fn connected_for(&self) -> Duration{
+        self.connected_at.elapsed()
+    }
+
+    /// Tells the peer's task to close the connection
+    /// Remembered until the task next waits in "disconnect_requested", so it is never missed
+    pub fn disconnect(&self){
+        self.disconnect.notify_one();
+    }
+
+    /// Waits until "disconnect" is called on any copy of this Tx
+    pub async fn disconnect_requested(&self){
+        self.disconnect.notified().await;
+    }
+}
+
+/// The receiving half of a peer's message queue
+pub struct Rx{
+    receiver: UnboundedReceiver<ChatMessage>,
+    queued: Arc<AtomicUsize>,
+}
+impl Rx{
+    /// Receives the next queued message, if the queue is still open
+    pub async fn recv(&mut self) -> Option<ChatMessage>{
+        let message = self.receiver.recv().await;
+        if message.is_some() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        message
+    }
+}
+
+/// Creates the message queue of the peer at "peer". Once "warn_threshold" messages are waiting
+/// in it, a warning is logged
+pub fn peer_channel(peer: SocketAddr, warn_threshold: usize) -> (Tx, Rx){
+    let (sender, receiver) = unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+
+    (
+        Tx{ sender, queued: Arc::clone(&queued), peer, warn_threshold, connected_at: Instant::now(), disconnect: Arc::new(Notify::new()) },
+        Rx{ receiver, queued },
+    )
+}
+
+/// Periodically logs how many peers are connected, and how many messages are queued for each
+/// Broadcast latencies are logged as well, with the "latency-stats" feature
+pub async fn log_stats(state: Arc<ServerState>) {
+    let mut ticker = tokio::time::interval(state.config().stats_interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        #[cfg(feature = "latency-stats")]
+        log::info!("Broadcast latency: {}", state.latencies.take_summary());
+
+        let actives = state.active_websockets.lock().await;
+        let usernames = state.con_to_username.lock().await;
+        let queues: Vec<String> = actives
+            .iter()
+            .map(|(addr, sender)| {
+                let username = usernames.get(addr).map(String::as_str).unwrap_or("Unknown");
+                format!("{username} ({addr}): {}", sender.queued())
+            })
+            .collect();
+
+        log::info!("Stats: {} peer(s) connected. Queued messages: [{}]", actives.len(), queues.join(", "));
+    }
+}
+
+/// Periodically tells every connected client how many messages were posted in each room, whenever that changed
+/// Clients only receive the messages of their current room, so this is all they learn about the others
+pub async fn send_activity(state: Arc<ServerState>) {
+    let mut ticker = tokio::time::interval(ACTIVITY_INTERVAL);
+    let mut last_sent = HashMap::new();
+
+    loop {
+        ticker.tick().await;
+
+        // Rooms which are gone are forgotten, so a room created again under the same name starts over
+        let activity = {
+            let mut activity = state.activity.lock().await;
+            let rooms = state.rooms.lock().await;
+            let permanent_rooms = &state.config().permanent_rooms;
+            activity.retain(|room, _| rooms.values().any(|current| current == room) || permanent_rooms.contains(room));
+            activity.clone()
+        };
+        if activity == last_sent {
+            continue;
+        }
+
+        let body = match serde_json::to_string(&activity) {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("Could not serialize the room activity: {err}");
+                continue;
+            }
+        };
+
+        let actives = state.active_websockets.lock().await;
+        let rooms = state.rooms.lock().await;
+        for (addr, sender) in actives.iter() {
+            let message = ChatMessage::builder()
+                .from(*addr)
+                .username(SYSTEM_USERNAME.to_string())
+                .body(body.clone())
+                .kind(MessageKind::RoomActivity)
+                .room(rooms.get(addr).cloned().unwrap_or(DEFAULT_ROOM.to_string()))
+                .build();
+
+            match message {
+                Some(message) => if let Err(send_error) = sender.send(message) {
+                    log::error!("Could not send the room activity to {addr}: {send_error}");
+                },
+                None => log::error!("Could not create the room activity message for {addr}"),
+            }
+        }
+
+        last_sent = activity;
+    }
+}
+
+/// Reloads the configuration whenever SIGHUP is received
+/// The current one is kept if the new one can't be read or has any invalid value
+#[cfg(unix)]
+pub async fn reload_on_hangup(state: Arc<ServerState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(err) => {
+            log::error!("Could not listen for SIGHUP: {err}");
+            return;
+        }
+    };
+
+    while sighup.recv().await.is_some() {
+        log::info!("Received SIGHUP. Reloading the configuration");
+        let config = match Config::load() {
+            Ok((config, problems)) if problems.is_empty() => config,
+            Ok((_, problems)) => {
+                log::error!("Keeping the current configuration, as the new one is invalid: {}", problems.join("; "));
+                continue;
+            }
+            Err(reason) => {
+                log::error!("Keeping the current configuration, as the new one could not be loaded: {reason}");
+                continue;
+            }
+        };
+
+        // Only names are logged, as some settings are secrets
+        let changes = state.config().changes(&config);
+        if changes.is_empty() {
+            log::info!("The configuration has not changed");
+            continue;
+        }
+        log::info!("Reloaded the configuration. Changed: {}", changes.join(", "));
+        let startup_only: Vec<&str> = changes.into_iter().filter(|name| STARTUP_ONLY_SETTINGS.contains(name)).collect();
+        if !startup_only.is_empty() {
+            log::warn!("These only take effect after a restart: {}", startup_only.join(", "));
+        }
+        state.set_config(config);
+    }
+}
+
+/// Resolves once the server is asked to stop, via Ctrl-C or, on Unix, SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            log::error!("Could not listen for Ctrl-C: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => _ = sigterm.recv().await,
+            Err(err) => {
+                log::error!("Could not listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    select! {
+        _ = ctrl_c => log::info!("Received Ctrl-C"),
+        _ = terminate => log::info!("Received SIGTERM"),
+    }
+}
 
 /// Closes a websocket stream that has been split into two
+/// The optional "reason" is sent along in the Close frame, for the client to display
+/// Gives up after SEND_TIMEOUT, so that a peer which neither reads nor acknowledges the Close can't keep
+/// the connection, and its task, around
 pub async fn close_websocket_stream(
     mut write: SplitSink<WebSocketStream<TcpStream>, Message>,
     mut read: SplitStream<WebSocketStream<TcpStream>>,
+    reason: Option<CloseFrame>,
 ) -> Result<(), Error> {
-    // Send a close message
-    write.send(Message::Close(None)).await?;
-
-    // Keep pulling from read stream until nothing more is left
-    while let Some(msg) = read.next().await {
-        match msg {
-            Ok(msg) => {
-                if msg.is_close() {
-                    return Ok(());
+    let closed = async {
+        // Send a close message
+        write.send(Message::Close(reason)).await?;
+
+        // Keep pulling from read stream until nothing more is left
+        while let Some(msg) = read.next().await {
+            match msg {
+                Ok(msg) => {
+                    if msg.is_close() {
+                        return Ok(());
+                    }
                 }
+                Err(e) => return Err(e),
             }
-            Err(e) => return Err(e),
         }
+        Ok(())
+    };
+
+    match timeout(SEND_TIMEOUT, closed).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Io(std::io::ErrorKind::TimedOut.into())),
     }
-    Ok(())
 }
 
-/// Waits for a message from the client and then broadcasts it to all the other
-/// connected piers.
+/// Handles "received", what was read from the client, broadcasting it to all the other connected piers.
+/// Only the read is raced against anything else by the connection loop, so that a message which was read is
+/// always handled in full, rather than dropped halfway through whenever something else comes first
+/// A client closing the connection returns "HandleError::Disconnected", and one which went away without
+/// saying goodbye returns "HandleError::ConnectionDropped". Announcing the departure is up to the caller
 pub async fn handle_received_from_client(
-    active_websockets: &PeerMap,
-    con_to_username: &UsernameMap,
-    stream_read: &mut SplitStream<WebSocketStream<TcpStream>>,
+    state: &ServerState,
+    received: Option<Result<Message, Error>>,
     client_addr: SocketAddr,
 ) -> Result<HandleResult, HandleError> {
 
-    let username = con_to_username
+    let username = state.con_to_username
         .lock()
         .await
         .get(&client_addr)
         .ok_or(HandleError::UnkownClient)?
         .to_owned();
 
-    match stream_read.next().await {
+    match received {
         Some(message_result) => {
             if let Ok(message) = message_result {
-                // Wrap the tungstenite message in a ChatMessage
-                let chat_message = ChatMessage::build(client_addr, username, message.to_string())
-                    .ok_or(HandleError::MalformedMessage)?;
+                // A client which closes the connection is leaving on purpose
+                if let Message::Close(frame) = message {
+                    let reason = frame.map(|frame| frame.reason.to_string()).unwrap_or_default();
+                    return Err(HandleError::Disconnected(reason));
+                }
 
-                broadcast_message(chat_message, active_websockets).await;
+                // Only text is chat. Pings are answered by tungstenite itself
+                let Message::Text(text) = message else {
+                    return Ok(HandleResult::ResponseSuccessful);
+                };
+
+                // Clients don't send blank messages, but any which do are ignored rather than shown as empty bubbles
+                let text = text.trim_end().to_string();
+                if text.is_empty() {
+                    log::debug!("Ignoring a blank message from {username} ({client_addr})");
+                    return Ok(HandleResult::ResponseSuccessful);
+                }
+
+                // Commands are handled by the server and not broadcast
+                if text.starts_with(COMMAND_PREFIX) {
+                    handle_command(&text, client_addr, &username, state).await;
+                    return Ok(HandleResult::ResponseSuccessful);
+                }
+
+                post_chat(text, client_addr, &username, None, state).await;
                 return Ok(HandleResult::ResponseSuccessful);
             }
 
@@ -70,36 +416,471 @@ pub async fn handle_received_from_client(
         }
         None => {
             log::info!("Client connection returned None. Removing client from connected peers");
+            Err(HandleError::ConnectionDropped)
+        }
+    }
+}
+
+/// Broadcasts "text", sent by the client at "client_addr", to its current room
+/// Read-only rooms, the length limit and redaction all apply. Messages with "expires_in" disappear from clients after it
+pub async fn post_chat(mut text: String, client_addr: SocketAddr, username: &str, expires_in: Option<Duration>, state: &ServerState) {
+    let ServerState{ active_websockets, rooms, .. } = state;
+    let config = state.config();  // Read once, so that a reload meanwhile can't mix two configurations
+
+    // Only admins may post in read-only rooms
+    let room = current_room(client_addr, rooms).await;
+    if !can_post(client_addr, &room, state).await {
+        if let Some(denial) = system_message(client_addr, &room, format!("Only admins may post in #{room}")) {
+            send_to_peer(denial, client_addr, active_websockets).await;
+        }
+        return;
+    }
+
+    // Messages over the length limit are either dropped or cut short, as configured
+    let length = text.graphemes(true).count();
+    if let Some(max_length) = config.max_message_length.filter(|max_length| length > *max_length) {
+        let notice = match config.long_messages {
+            LongMessages::Reject => format!("Your message is {length} characters long, over the limit of {max_length}. It was not sent"),
+            LongMessages::Truncate => {
+                text = truncate(&text, max_length);
+                format!("Your message was cut to {max_length} of its {length} characters")
+            }
+        };
+        if let Some(notice) = system_message(client_addr, &room, notice) {
+            send_to_peer(notice, client_addr, active_websockets).await;
+        }
+        if matches!(config.long_messages, LongMessages::Reject) {
+            return;
+        }
+    }
+
+    let mut builder = ChatMessage::builder()
+        .from(client_addr)
+        .username(username.to_string())
+        .body(redact(&text, &config.redacted_words))
+        .room(room.clone());
+    if let Some(expires_in) = expires_in {
+        builder = builder.expires_in(expires_in);
+    }
+    let Some(chat_message) = builder.build() else {
+        log::error!("Could not create chat message from {client_addr}");
+        return;
+    };
+
+    // Senders show their own chat right away, but can't know when it disappears until it comes back
+    if expires_in.is_some() {
+        send_to_peer(chat_message.clone(), client_addr, active_websockets).await;
+    }
+
+    // Let a lonely client know nobody received their message, once for as long as it stays alone
+    if broadcast_message(chat_message, Audience::Others, state).await == 0 && state.told_alone.lock().await.insert(client_addr) {
+        if let Some(hint) = system_message(client_addr, &room, format!("You're the only one in #{room}")) {
+            send_to_peer(hint, client_addr, active_websockets).await;
+        }
+    }
+
+    auto_respond(&text, client_addr, &room, state).await;
+}
+
+/// Has the bot answer "text", just posted by the client at "client_addr" in "room", if it mentions a trigger
+/// The bot only ever answers users, never itself, and the same trigger at most once per cooldown in each room
+async fn auto_respond(text: &str, client_addr: SocketAddr, room: &str, state: &ServerState) {
+    let config = state.config();
+    let Some(auto_response) = find_response(text, &config.auto_responses) else {
+        return;
+    };
+    if !state.auto_response_cooldowns.try_answer(room, &auto_response.trigger, config.auto_response_cooldown) {
+        log::debug!("Not answering \"{}\" in #{room} again so soon", auto_response.trigger);
+        return;
+    }
+
+    let answer = ChatMessage::builder()
+        .from(client_addr)
+        .username(config.bot_name.clone())
+        .body(auto_response.response.clone())
+        .room(room.to_string())
+        .build();
+    let Some(answer) = answer else {
+        log::error!("Could not create the auto-response to {client_addr}");
+        return;
+    };
+
+    // The sender asked, so it should see the answer as well
+    broadcast_message(answer, Audience::Everyone, state).await;
+}
+
+/// Cuts "text" down to "max_length" characters, the last of which is an ellipsis
+/// Characters are counted as graphemes, so that none is ever split in half
+fn truncate(text: &str, max_length: usize) -> String {
+    let mut truncated: String = text.graphemes(true).take(max_length.saturating_sub(1)).collect();
+    truncated.push(ELLIPSIS);
+    truncated
+}
+
+/// Whether "given" is the same as "secret", taking as long for every "given" of the same length
+/// so that how long the comparison takes doesn't tell how much of a secret was guessed right
+pub fn secrets_match(given: &str, secret: &str) -> bool {
+    given.len() == secret.len() && given.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Tells the room of "username" that they left, with "farewell" i.e. "has left the channel"
+/// With a flap window configured, this only happens once the window is over, unless they came back meanwhile
+pub async fn announce_departure(addr: SocketAddr, username: String, room: String, farewell: &'static str, state: Arc<ServerState>) {
+    let window = state.config().flap_window;
+    if !window.is_zero() {
+        let at = Instant::now();
+        state.departures.lock().await.insert(username.clone(), Departure{ room: room.clone(), at });
+        tokio::time::sleep(window).await;
+
+        // Coming back removes the departure, and leaving again replaces it
+        let mut departures = state.departures.lock().await;
+        if departures.get(&username).is_none_or(|departure| departure.at != at) {
+            return;
+        }
+        departures.remove(&username);
+    }
+
+    match system_message(addr, &room, format!("{username} {farewell}")) {
+        Some(exit_message) => {
+            log::info!("Broadcasting {username}'s exit message");
+            _ = broadcast_message(exit_message, Audience::Others, &state).await;
+        },
+        None => log::error!("Could not create user {username}'s exit broadcast message"),
+    }
+}
+
+/// Whether "username" is coming back to "room" within the flap window, in which case neither its departure
+/// nor its return are announced
+pub async fn is_flapping(username: &str, room: &str, state: &ServerState) -> bool {
+    let mut departures = state.departures.lock().await;
+    if departures.get(username).is_none_or(|departure| departure.room != room) {
+        return false;
+    }
+
+    let departure = departures.remove(username);
+    log::debug!(
+        "{username} came back to #{room} {} ms after leaving. Not announcing it",
+        departure.map(|departure| departure.at.elapsed().as_millis()).unwrap_or_default()
+    );
+    true
+}
+
+/// Builds a SYSTEM message for "room". "addr" is the client the message concerns, which it is not
+/// broadcast to
+pub fn system_message(addr: SocketAddr, room: &str, body: String) -> Option<ChatMessage> {
+    ChatMessage::builder()
+        .from(addr)
+        .username(SYSTEM_USERNAME.to_string())
+        .body(body)
+        .kind(MessageKind::System)
+        .room(room.to_string())
+        .build()
+}
+
+/// Builds the message telling the client at "addr" that it is now in "room"
+pub fn joined_message(addr: SocketAddr, room: &str) -> Option<ChatMessage> {
+    ChatMessage::builder()
+        .from(addr)
+        .username(SYSTEM_USERNAME.to_string())
+        .body(room.to_string())
+        .kind(MessageKind::Joined)
+        .room(room.to_string())
+        .build()
+}
+
+/// Whether the client at "addr" may post in "room". Only admins may do so in read-only rooms
+pub async fn can_post(addr: SocketAddr, room: &str, state: &ServerState) -> bool {
+    !state.read_only_rooms.lock().await.contains(room) || state.admins.lock().await.contains(&addr)
+}
+
+/// Tells the client at "addr" whether it may post in "room"
+pub async fn send_room_access(addr: SocketAddr, room: &str, state: &ServerState) {
+    let access = if can_post(addr, room, state).await {ROOM_WRITABLE} else {ROOM_READ_ONLY};
+    let message = ChatMessage::builder()
+        .from(addr)
+        .username(SYSTEM_USERNAME.to_string())
+        .body(access.to_string())
+        .kind(MessageKind::RoomAccess)
+        .room(room.to_string())
+        .build();
+
+    match message {
+        Some(message) => _ = send_to_peer(message, addr, &state.active_websockets).await,
+        None => log::error!("Could not create room access message for {addr}"),
+    }
+}
+
+/// Whether a client other than the one at "addr" has "username" within "scope" of "room"
+/// Clients still logging in have a username but no room yet, so they count as being in every room
+pub fn is_taken(username: &str, addr: SocketAddr, room: &str, scope: UsernameScope, usernames: &HashMap<SocketAddr, String>, rooms: &HashMap<SocketAddr, String>) -> bool {
+    usernames.iter().any(|(other_addr, name)| name == username && *other_addr != addr && match scope {
+        UsernameScope::Server => true,
+        UsernameScope::Room => rooms.get(other_addr).is_none_or(|other_room| other_room == room),
+    })
+}
 
-            // Broadcast exit of current user
-            match ChatMessage::build(client_addr, "SYSTEM".to_string(), format!("{username} has exited the channel")){
-                Some(exit_message) => {
-                    log::info!("Broadcasting {username}'s exit message");
-                    broadcast_message(exit_message, active_websockets).await;
+/// Gives "username" to the client at "addr", if it is valid and nobody else has it, on the server or in "room"
+/// as set by USERNAME_SCOPE
+pub async fn claim_username(addr: SocketAddr, username: &str, room: &str, state: &ServerState) -> Result<(), String> {
+    validate_username(username)?;
+
+    // Check and update under the same locks, so two clients can't grab the same name
+    let scope = state.config().username_scope;
+    let rooms = state.rooms.lock().await;
+    let mut usernames = state.con_to_username.lock().await;
+    if is_taken(username, addr, room, scope, &usernames, &rooms) {
+        return Err(match scope {
+            UsernameScope::Server => format!("username \"{username}\" is already taken"),
+            UsernameScope::Room => format!("username \"{username}\" is already taken in #{room}"),
+        });
+    }
+    usernames.insert(addr, username.to_string());
+    Ok(())
+}
+
+/// Gives "username" to the client at "addr" like "claim_username", but if it is taken, the first free one of
+/// "username2" up to "username{max_suffix}" instead. Returns the username given
+/// Names are cut short as needed to fit the suffix within MAX_USERNAME_LENGTH
+pub async fn claim_free_username(addr: SocketAddr, username: &str, room: &str, max_suffix: u32, state: &ServerState) -> Result<String, String> {
+    validate_username(username)?;
+
+    let scope = state.config().username_scope;
+    let rooms = state.rooms.lock().await;
+    let mut usernames = state.con_to_username.lock().await;
+    let is_free = |candidate: &str| !is_taken(candidate, addr, room, scope, &usernames, &rooms);
+    let free = std::iter::once(username.to_string())
+        .chain((2..=max_suffix).map(|suffix| {
+            let suffix = suffix.to_string();
+            let base: String = username.chars().take(MAX_USERNAME_LENGTH - suffix.len()).collect();
+            base + &suffix
+        }))
+        .find(|candidate| is_free(candidate) && validate_username(candidate).is_ok())
+        .ok_or(format!("username \"{username}\" and its variants up to \"{username}{max_suffix}\" are all taken"))?;
+
+    usernames.insert(addr, free.clone());
+    Ok(free)
+}
+
+/// Returns the room the client at "addr" is in
+pub async fn current_room(addr: SocketAddr, rooms: &RoomMap) -> String {
+    rooms
+        .lock()
+        .await
+        .get(&addr)
+        .cloned()
+        .unwrap_or(DEFAULT_ROOM.to_string())
+}
+
+/// Moves the client at "addr" into "room", creating the room if nobody is in it yet
+/// Fails without moving the client if that would take the server past its room limit
+pub async fn enter_room(addr: SocketAddr, room: &str, rooms: &RoomMap, config: &Config) -> Result<(), String> {
+    // Check and update under the same lock, so two clients can't both create the last room
+    let mut rooms = rooms.lock().await;
+    let old_room = rooms.remove(&addr);
+
+    let is_new = !config.is_permanent_room(room) && !rooms.values().any(|member_room| member_room == room);
+    if is_new {
+        let existing: HashSet<&String> = rooms.values().chain(config.permanent_rooms.iter()).collect();
+        if existing.len() >= config.max_rooms {
+            if let Some(old_room) = old_room {
+                rooms.insert(addr, old_room);
+            }
+            return Err(format!("the server already has the maximum of {} rooms", config.max_rooms));
+        }
+        log::info!("Room #{room} created by {addr}");
+    }
+
+    rooms.insert(addr, room.to_string());
+    if let Some(old_room) = old_room {
+        log_if_deleted(&old_room, &rooms, config);
+    }
+    Ok(())
+}
+
+/// Lets everyone in "room" be told they are alone again, as someone just entered it
+pub async fn reset_alone(room: &str, state: &ServerState) {
+    let rooms = state.rooms.lock().await;
+    state.told_alone.lock().await.retain(|addr| rooms.get(addr).is_none_or(|member_room| member_room != room));
+}
+
+/// Takes the client at "addr" out of its room, deleting the room if it was the last one in it
+pub async fn leave_room(addr: SocketAddr, rooms: &RoomMap, config: &Config) {
+    let mut rooms = rooms.lock().await;
+    if let Some(old_room) = rooms.remove(&addr) {
+        log_if_deleted(&old_room, &rooms, config);
+    }
+}
+
+/// Logs the deletion of "room" if nobody is left in it
+/// Rooms only exist through their members, so there is nothing else to remove
+fn log_if_deleted(room: &str, rooms: &HashMap<SocketAddr, String>, config: &Config) {
+    if !config.is_permanent_room(room) && !rooms.values().any(|member_room| member_room == room) {
+        log::info!("Room #{room} deleted, its last member left");
+    }
+}
+
+/// Tells every connected client who is in its room, which rooms exist and which colors users picked
+/// Meant to be called whenever a client enters, leaves or changes rooms, is renamed or picks a color
+pub async fn send_directory(state: &ServerState) {
+    // Take a snapshot, so that no lock is held while sending
+    let (members, rosters, room_list, colors) = {
+        let rooms = state.rooms.lock().await;
+        let usernames = state.con_to_username.lock().await;
+        let colors: Vec<(SocketAddr, String, String)> = state
+            .colors
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(addr, color)| Some((*addr, usernames.get(addr)?.clone(), color.clone())))
+            .collect();
+
+        let mut rosters: HashMap<String, Vec<String>> = HashMap::new();
+        for (addr, room) in rooms.iter() {
+            if let Some(username) = usernames.get(addr) {
+                rosters.entry(room.clone()).or_default().push(username.clone());
+            }
+        }
+        for roster in rosters.values_mut() {
+            roster.sort();
+        }
+
+        let mut room_list: Vec<String> = rooms.values().chain(state.config().permanent_rooms.iter()).cloned().collect();
+        room_list.sort();
+        room_list.dedup();
+
+        (rooms.clone(), rosters, room_list, colors)
+    };
+
+    let room_list = match serde_json::to_string(&room_list) {
+        Ok(room_list) => room_list,
+        Err(err) => {
+            log::error!("Could not serialize the directory: {err}");
+            return;
+        }
+    };
+
+    // Colors are keyed by username, which may repeat across rooms if scoped to them. Each room is then only
+    // told the colors of its own members
+    let scope = state.config().username_scope;
+    let mut colors_by_room: HashMap<Option<&String>, HashMap<&str, &str>> = HashMap::new();
+    for (addr, username, color) in colors.iter() {
+        let room = match scope {
+            UsernameScope::Server => None,
+            UsernameScope::Room => members.get(addr),
+        };
+        colors_by_room.entry(room).or_default().insert(username, color);
+    }
+    let colors_by_room: HashMap<Option<&String>, String> = colors_by_room
+        .into_iter()
+        .map(|(room, colors)| (room, serde_json::to_string(&colors).unwrap_or_default()))
+        .collect();
+    let colors_in = |room: &String| {
+        let key = if scope == UsernameScope::Server {None} else {Some(room)};
+        colors_by_room.get(&key).cloned().unwrap_or("{}".to_string())
+    };
+
+    let actives = state.active_websockets.lock().await;
+    for (addr, room) in members.iter() {
+        let Some(sender) = actives.get(addr) else {
+            continue;
+        };
+        let roster = serde_json::to_string(rosters.get(room).unwrap_or(&Vec::new())).unwrap_or_default();
+
+        for (kind, body) in [(MessageKind::Roster, roster), (MessageKind::RoomList, room_list.clone()), (MessageKind::Colors, colors_in(room))] {
+            let message = ChatMessage::builder()
+                .from(*addr)
+                .username(SYSTEM_USERNAME.to_string())
+                .body(body)
+                .kind(kind)
+                .room(room.clone())
+                .build();
+
+            match message {
+                Some(message) => if let Err(send_error) = sender.send(message) {
+                    log::error!("Could not send the directory to {addr}: {send_error}");
                 },
-                None => log::error!("Could not create user {username}'s exit broadcast message"),
+                None => log::error!("Could not create the directory message for {addr}"),
             }
+        }
+    }
+}
 
-            Err(HandleError::ConnectionDropped)
+/// Sends a message to a single connected websocket in 'active_websockets'
+/// Returns whether the message could be queued for that client
+pub async fn send_to_peer(message: ChatMessage, addr: SocketAddr, active_websockets: &PeerMap) -> bool {
+    match active_websockets.lock().await.get(&addr) {
+        Some(sender) => match sender.send(message) {
+            Ok(_) => true,
+            Err(send_error) => {
+                log::error!("Could not send message to {addr}: {send_error}");
+                false
+            }
+        },
+        None => {
+            log::error!("Could not send message to {addr}: not connected");
+            false
         }
     }
 }
 
-/// Broadcasts a message to all connected websockets in 'active_websockets'
-pub async fn broadcast_message(message: ChatMessage, active_websockets: &PeerMap) {
+/// Who a broadcast is delivered to, among the clients in the rooms it targets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Audience{
+    /// Everyone but the sender. For chat, which clients show as soon as it is typed, and for notices about
+    /// the sender which it learns of through a confirmation of its own, i.e. "Joined" when entering a room,
+    /// "Rename" when renamed, or nothing at all when it leaves
+    Others,
+    /// Everyone, sender included. For what the sender has no other way of seeing: tables, which clients only
+    /// show once echoed back, the bot's answers, and clearing a room, along with its notice
+    Everyone,
+}
+
+/// Broadcasts a message to "audience" in the message's room
+/// Returns the number of clients the message was delivered to, which may be zero
+pub async fn broadcast_message(message: ChatMessage, audience: Audience, state: &ServerState) -> usize {
+    let target_rooms = HashSet::from([message.get_room()]);
+    broadcast_to_rooms(message, &target_rooms, audience, state).await
+}
+
+/// Broadcasts a message to "audience" in any of "target_rooms"
+/// Returns the number of clients the message was delivered to, which may be zero
+/// Every message is also printed to stdout, if the server is configured to do so
+pub async fn broadcast_to_rooms(message: ChatMessage, target_rooms: &HashSet<String>, audience: Audience, state: &ServerState) -> usize {
     let mut inactive_addrs: Vec<SocketAddr> = Vec::new();
+    let mut recipients = 0usize;
+
+    if state.config().echo_messages {
+        let mut room_names: Vec<String> = target_rooms.iter().map(|room| format!("#{room}")).collect();
+        room_names.sort();
+        println!("[{}] {message}", room_names.join(", "));
+    }
+
+    if matches!(message.get_kind(), MessageKind::Chat | MessageKind::Table) {
+        let mut activity = state.activity.lock().await;
+        for room in target_rooms {
+            *activity.entry(room.clone()).or_default() += 1;
+        }
+    }
 
     // Broadcasts a message to all clients connected in active_websockets
-    let mut actives = active_websockets.lock().await;
+    let mut actives = state.active_websockets.lock().await;
+    let rooms = state.rooms.lock().await;
 
     for (addr, sender) in actives.iter() {
-        if *addr == message.get_addr() {
+        let Some(room) = rooms.get(addr).filter(|room| target_rooms.contains(*room)) else {
+            continue;
+        };
+        if audience == Audience::Others && *addr == message.get_addr() {
             continue;
         }
 
-        if let Err(send_error) = sender.send(message.clone()) {
-            log::error!("Could not broadcast message to {addr}: {send_error}");
-            inactive_addrs.push(*addr);
+        // Each copy is tagged with the room it is delivered into, see "handle_received_from_server"
+        match sender.send(message.clone().in_room(room.clone())) {
+            Ok(_) => recipients += 1,
+            Err(send_error) => {
+                log::error!("Could not broadcast message to {addr}: {send_error}");
+                inactive_addrs.push(*addr);
+            }
         }
     }
 
@@ -108,37 +889,349 @@ pub async fn broadcast_message(message: ChatMessage, active_websockets: &PeerMap
         log::debug!("Removing inactive channel for addr {inactive}");
         actives.remove(&inactive);
     }
+
+    if recipients == 0 {
+        log::debug!("No other clients in the target room(s), message from {} reached no one", message.get_addr());
+    }
+
+    recipients
 }
 
-/// Relays message to specified client
+/// What "handle_received_from_server" was in the middle of, kept by the caller across calls
+/// The connection loop drops that future whenever another event comes first, so this is what lets it pick up where
+/// it left off
+#[derive(Default)]
+pub struct PendingSend{
+    /// Taken off the queue, but not handed to the connection yet
+    message: Option<ChatMessage>,
+    /// When the send in progress started. Without it, a peer which stopped reading but keeps writing would
+    /// restart SEND_TIMEOUT every time
+    stalled_since: Option<Instant>,
+}
+
+/// Relays the next queued message to the client at "addr", unless it belongs to a room the client has left since
+/// Nothing is lost if this is dropped halfway through, as long as "pending" is passed in again
 pub async fn handle_received_from_server(
-    rx: &mut UnboundedReceiver<ChatMessage>,
-    write: &mut SplitSink<WebSocketStream<TcpStream>, Message>
+    addr: SocketAddr,
+    rx: &mut Rx,
+    write: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
+    pending: &mut PendingSend,
+    state: &ServerState,
 ) -> Result<HandleResult, HandleError> {
-    match rx.recv().await {
-        Some(message) => {
-            // Create a ClientMessage
-            let client_msg = ClientMessage::from(message);
-
-            // Serialize and send
-            match serde_json::to_string(&client_msg) {
-                Ok(ser_msg) => {
-                    if write.send(Message::Text(ser_msg.into())).await.is_err() {
-                        log::error!("Could not send message back to client");
-                        return Err(HandleError::ConnectionDropped);
-                    }
+    // An interrupted send is finished first, in the time it had left
+    if let Some(since) = pending.stalled_since {
+        match timeout(SEND_TIMEOUT.saturating_sub(since.elapsed()), write.flush()).await {
+            Ok(Ok(())) => pending.stalled_since = None,
+            Ok(Err(_)) => {
+                log::error!("Could not send message back to client");
+                return Err(HandleError::ConnectionDropped);
+            }
+            Err(_) => {
+                log::error!("Client did not accept message within {} s", SEND_TIMEOUT.as_secs());
+                return Err(HandleError::ConnectionDropped);
+            }
+        }
+    }
 
-                    Ok(HandleResult::ResponseSuccessful)
-                }
-                Err(err) => {
-                    log::error!("Could not serialize message to be relayed to client: {err}");
-                    Err(HandleError::MalformedMessage)
-                }
+    if pending.message.is_none() {
+        pending.message = rx.recv().await;
+    }
+    let Some(message) = &pending.message else {
+        log::error!("Nothing came back from recv :(");
+        return Err(HandleError::MalformedMessage);
+    };
+
+    // Messages queued before the client changed rooms are dropped, rather than shown in the new one
+    if message.get_kind().is_room_bound() && state.rooms.lock().await.get(&addr).is_some_and(|room| *room != message.get_room()) {
+        log::debug!("Dropping a message from #{} queued for {addr} before it changed rooms", message.get_room());
+        pending.message = None;
+        return Ok(HandleResult::ResponseSuccessful);
+    }
+
+    #[cfg(feature = "latency-stats")]
+    let received_at = message.get_timestamp();
+
+    // Serialize and send
+    let ser_msg = match serde_json::to_string(&ClientMessage::from(message.clone())) {
+        Ok(ser_msg) => ser_msg,
+        Err(err) => {
+            log::error!("Could not serialize message to be relayed to client: {err}");
+            pending.message = None;
+            return Err(HandleError::MalformedMessage);
+        }
+    };
+
+    // A peer which stopped reading would otherwise block this send forever
+    // The message only leaves "pending" once the connection took it, the rest of the send being finished by "flush"
+    pending.stalled_since = Some(Instant::now());
+    let send = async {
+        write.feed(Message::Text(ser_msg.into())).await?;
+        pending.message = None;
+        write.flush().await
+    };
+    match timeout(SEND_TIMEOUT, send).await {
+        Ok(Ok(())) => {
+            pending.stalled_since = None;
+            #[cfg(feature = "latency-stats")]
+            state.latencies.record(received_at.elapsed());
+            Ok(HandleResult::ResponseSuccessful)
+        }
+        Ok(Err(_)) => {
+            log::error!("Could not send message back to client");
+            Err(HandleError::ConnectionDropped)
+        }
+        Err(_) => {
+            log::error!("Client did not accept message within {} s", SEND_TIMEOUT.as_secs());
+            Err(HandleError::ConnectionDropped)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+    const PEER: &str = "127.0.0.1:5003";
+
+    fn message() -> ChatMessage {
+        ChatMessage::build("127.0.0.1:5000".parse().unwrap(), "bob".to_string(), "hi".to_string()).unwrap()
+    }
+
+    /// A message big enough for a few of them to fill the socket buffers of a peer which doesn't read
+    fn big_message() -> ChatMessage {
+        ChatMessage::build("127.0.0.1:5000".parse().unwrap(), "bob".to_string(), "x".repeat(60_000)).unwrap()
+    }
+
+    fn state() -> ServerState {
+        ServerState{
+            active_websockets: Default::default(),
+            con_to_username: Default::default(),
+            rooms: Default::default(),
+            admins: Default::default(),
+            read_only_rooms: Default::default(),
+            departures: Default::default(),
+            colors: Default::default(),
+            last_seen: Default::default(),
+            activity: Default::default(),
+            told_alone: Default::default(),
+            invites: Default::default(),
+            started_at: Instant::now(),
+            auto_response_cooldowns: Default::default(),
+            #[cfg(feature = "latency-stats")]
+            latencies: Default::default(),
+            config: std::sync::RwLock::new(Arc::new(Config::load().unwrap().0)),
+        }
+    }
+
+    /// Both halves of the server's end of a new connection, along with the client's end
+    async fn connection() -> (
+        SplitSink<WebSocketStream<TcpStream>, Message>,
+        SplitStream<WebSocketStream<TcpStream>>,
+        WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(
+            tokio_tungstenite::connect_async(url),
+            async { tokio_tungstenite::accept_async(listener.accept().await.unwrap().0).await.unwrap() },
+        );
+        let (write, read) = server.split();
+        (write, read, client.unwrap().0)
+    }
+
+    /// Relays queued messages until one can't be sent for a while, as the peer stopped reading
+    async fn send_until_stalled(rx: &mut Rx, write: &mut SplitSink<WebSocketStream<TcpStream>, Message>, pending: &mut PendingSend, state: &ServerState) {
+        while let Ok(result) = timeout(Duration::from_millis(200), handle_received_from_server(PEER.parse().unwrap(), rx, write, pending, state)).await {
+            assert!(result.is_ok());
+        }
+        assert!(pending.stalled_since.is_some());
+    }
+
+    #[tokio::test]
+    async fn stalled_send_notices_a_dropped_peer_promptly() {
+        let state = state();
+        let (mut write, _read, client) = connection().await;
+        let (tx, mut rx) = peer_channel(PEER.parse().unwrap(), usize::MAX);
+        for _ in 0..400 {
+            tx.send(big_message()).unwrap();
+        }
+        let mut pending = PendingSend::default();
+        send_until_stalled(&mut rx, &mut write, &mut pending, &state).await;
+
+        drop(client);
+        let started = Instant::now();
+        let result = handle_received_from_server(PEER.parse().unwrap(), &mut rx, &mut write, &mut pending, &state).await;
+        assert!(matches!(result, Err(HandleError::ConnectionDropped)));
+        assert!(started.elapsed() < SEND_TIMEOUT / 2);
+    }
+
+    #[tokio::test]
+    async fn peer_which_stops_reading_but_keeps_writing_is_dropped() {
+        let state = state();
+        let (mut write, mut read, client) = connection().await;
+        let (tx, mut rx) = peer_channel(PEER.parse().unwrap(), usize::MAX);
+        for _ in 0..400 {
+            tx.send(big_message()).unwrap();
+        }
+
+        // Never reads, but keeps the read half of the server busy
+        let (mut client_write, _client_read) = client.split();
+        tokio::spawn(async move {
+            while client_write.send(Message::Text("still here".into())).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
             }
+        });
+
+        // Like the connection loop, which drops the send whenever something is read first
+        let started = Instant::now();
+        let mut pending = PendingSend::default();
+        let result = loop {
+            select! {
+                _ = read.next() => {},
+                result = handle_received_from_server(PEER.parse().unwrap(), &mut rx, &mut write, &mut pending, &state) => if result.is_err() {
+                    break result;
+                },
+            }
+        };
+        assert!(matches!(result, Err(HandleError::ConnectionDropped)));
+        assert!(started.elapsed() < SEND_TIMEOUT + Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn interrupted_send_loses_nothing() {
+        let state = state();
+        let (mut write, _read, mut client) = connection().await;
+        let (tx, mut rx) = peer_channel(PEER.parse().unwrap(), usize::MAX);
+        tx.send(message()).unwrap();
+        let mut pending = PendingSend::default();
+
+        // Dropped after taking the message off the queue, while waiting for the rooms
+        {
+            let _rooms = state.rooms.lock().await;
+            let send = handle_received_from_server(PEER.parse().unwrap(), &mut rx, &mut write, &mut pending, &state);
+            assert!(timeout(Duration::from_millis(50), send).await.is_err());
         }
-        None => {
-            log::error!("Nothing came back from recv :(");
-            Err(HandleError::MalformedMessage)
-        },
+        assert_eq!(tx.queued(), 0);
+
+        let result = handle_received_from_server(PEER.parse().unwrap(), &mut rx, &mut write, &mut pending, &state).await;
+        assert!(result.is_ok());
+        let received = client.next().await.unwrap().unwrap();
+        let client_message: ClientMessage = serde_json::from_str(received.to_text().unwrap()).unwrap();
+        assert_eq!(client_message.get_message(), "hi");
+    }
+
+    /// How many "only one" hints are waiting in "rx"
+    fn alone_hints(rx: &mut Rx) -> usize {
+        let mut hints = 0;
+        while let Ok(message) = rx.receiver.try_recv() {
+            hints += usize::from(message.get_message().starts_with("You're the only one"));
+        }
+        hints
+    }
+
+    #[tokio::test]
+    async fn lonely_client_is_told_once_until_someone_enters() {
+        let state = state();
+        let (alice, bob) = ("127.0.0.1:5010".parse().unwrap(), "127.0.0.1:5011".parse().unwrap());
+        let (tx, mut rx) = peer_channel(alice, usize::MAX);
+        state.active_websockets.lock().await.insert(alice, tx);
+        enter_room(alice, DEFAULT_ROOM, &state.rooms, &state.config()).await.unwrap();
+
+        for _ in 0..3 {
+            post_chat("hi".to_string(), alice, "alice", None, &state).await;
+        }
+        assert_eq!(alone_hints(&mut rx), 1);
+
+        // Someone dropping by starts a new stretch of being alone
+        enter_room(bob, DEFAULT_ROOM, &state.rooms, &state.config()).await.unwrap();
+        reset_alone(DEFAULT_ROOM, &state).await;
+        leave_room(bob, &state.rooms, &state.config()).await;
+        post_chat("anyone?".to_string(), alice, "alice", None, &state).await;
+        post_chat("hello?".to_string(), alice, "alice", None, &state).await;
+        assert_eq!(alone_hints(&mut rx), 1);
+    }
+
+    #[test]
+    fn truncate_keeps_graphemes_whole() {
+        assert_eq!(truncate("hello world", 5), "hell…");
+        assert_eq!(truncate("hello world", 5).chars().count(), 5);
+
+        // A family emoji is a single grapheme made of several chars
+        let family = "👨‍👩‍👧";
+        assert_eq!(truncate(&format!("ab{family}cd"), 4), format!("ab{family}…"));
+        assert_eq!(truncate(&format!("abc{family}d"), 4), "abc…");
+        assert_eq!(truncate("e\u{301}e\u{301}e\u{301}", 3), "e\u{301}e\u{301}…");
+    }
+
+    #[tokio::test]
+    async fn close_gives_up_on_a_peer_which_never_acknowledges() {
+        // The client is never polled, so it never answers the Close
+        let (write, read, _client) = connection().await;
+        let started = Instant::now();
+        assert!(close_websocket_stream(write, read, None).await.is_err());
+        assert!(started.elapsed() < SEND_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn disconnecting_a_peer_which_stopped_reading_ends() {
+        let state = state();
+        let (mut write, read, _client) = connection().await;
+        let (tx, mut rx) = peer_channel(PEER.parse().unwrap(), usize::MAX);
+        for _ in 0..400 {
+            tx.send(big_message()).unwrap();
+        }
+        let mut pending = PendingSend::default();
+        send_until_stalled(&mut rx, &mut write, &mut pending, &state).await;
+
+        // Like "/disconnect", whose Close can't even be written to the full socket
+        let started = Instant::now();
+        let reason = shared::close_frame(CloseCode::Policy, "disconnected by an admin");
+        assert!(close_websocket_stream(write, read, Some(reason)).await.is_err());
+        assert!(started.elapsed() < SEND_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn secrets_are_compared_whole() {
+        assert!(secrets_match("hunter2", "hunter2"));
+        assert!(!secrets_match("hunter3", "hunter2"));
+        assert!(!secrets_match("hunter", "hunter2"));
+        assert!(!secrets_match("hunter22", "hunter2"));
+        assert!(!secrets_match("", "hunter2"));
+    }
+
+    #[tokio::test]
+    async fn slow_peer_queue_depth() {
+        let (tx, mut rx) = peer_channel("127.0.0.1:5001".parse().unwrap(), 3);
+        for _ in 0..5 {
+            tx.send(message()).unwrap();
+        }
+        assert_eq!(tx.queued(), 5);
+
+        rx.recv().await.unwrap();
+        rx.recv().await.unwrap();
+        assert_eq!(tx.queued(), 3);
+
+        // A peer which is gone doesn't count what it will never pick up
+        drop(rx);
+        assert!(tx.send(message()).is_err());
+        assert_eq!(tx.queued(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn queue_depth_never_wraps() {
+        let (tx, mut rx) = peer_channel("127.0.0.1:5002".parse().unwrap(), usize::MAX);
+        let sender = tokio::spawn(async move {
+            for _ in 0..10_000 {
+                tx.send(message()).unwrap();
+                assert!(tx.queued() <= 10_000);
+            }
+            tx
+        });
+        for _ in 0..10_000 {
+            rx.recv().await.unwrap();
+        }
+        assert_eq!(sender.await.unwrap().queued(), 0);
     }
 }
@@ -0,0 +1,107 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   One-time tokens handed out by "/invite", letting someone in     #
+//   without the AUTH_TOKEN                                          #
+//********************************************************************
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::{Duration, Instant}};
+
+use rand::{distr::Alphanumeric, Rng};
+
+// Constants
+const INVITE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_INVITES: usize = 1000;     // Pending at once, so that "/invite" can't be used to eat memory
+const TOKEN_LENGTH: usize = 32;
+
+/// An issued token
+struct Issued{
+    at: Instant,
+    /// The address of whoever used it first, which may keep using it to reconnect
+    redeemed_by: Option<IpAddr>,
+}
+
+/// The invite tokens issued within INVITE_TTL
+/// Kept behind a blocking mutex, as they are checked during the synchronous handshake callback
+#[derive(Default)]
+pub struct Invites{
+    tokens: Mutex<HashMap<String, Issued>>,
+}
+impl Invites{
+    /// Issues a new token, valid for a single client within INVITE_TTL
+    /// Returns None if there are already MAX_INVITES pending
+    pub fn issue(&self) -> Option<String>{
+        let mut tokens = self.tokens.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        tokens.retain(|_, issued| issued.at.elapsed() < INVITE_TTL);
+        if tokens.len() >= MAX_INVITES {
+            return None;
+        }
+
+        let token: String = rand::rng().sample_iter(&Alphanumeric).take(TOKEN_LENGTH).map(char::from).collect();
+        tokens.insert(token.clone(), Issued{ at: Instant::now(), redeemed_by: None });
+        Some(token)
+    }
+
+    /// Whether "token" lets the client at "ip" in: either it was not used yet, which binds it to "ip",
+    /// or that same address used it before and is reconnecting
+    pub fn redeem(&self, token: &str, ip: IpAddr) -> bool{
+        let mut tokens = self.tokens.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(issued) = tokens.get_mut(token) else {
+            return false;
+        };
+        if issued.at.elapsed() >= INVITE_TTL {
+            tokens.remove(token);
+            return false;
+        }
+
+        *issued.redeemed_by.get_or_insert(ip) == ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const BOB: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn tokens_are_used_once() {
+        let invites = Invites::default();
+        let token = invites.issue().unwrap();
+        assert!(invites.redeem(&token, ALICE));
+        assert!(!invites.redeem(&token, BOB));
+        assert!(!invites.redeem("not issued", ALICE));
+    }
+
+    #[test]
+    fn redeemed_tokens_let_the_same_client_reconnect() {
+        let invites = Invites::default();
+        let token = invites.issue().unwrap();
+        assert!(invites.redeem(&token, ALICE));
+        assert!(invites.redeem(&token, ALICE));
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let invites = Invites::default();
+        let token = invites.issue().unwrap();
+        if let Some(long_ago) = Instant::now().checked_sub(INVITE_TTL) {
+            invites.tokens.lock().unwrap().get_mut(&token).unwrap().at = long_ago;
+            assert!(!invites.redeem(&token, ALICE));
+        }
+    }
+
+    #[test]
+    fn pending_invites_are_limited() {
+        let invites = Invites::default();
+        for _ in 0..MAX_INVITES {
+            assert!(invites.issue().is_some());
+        }
+        assert!(invites.issue().is_none());
+    }
+}
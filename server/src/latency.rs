@@ -0,0 +1,43 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Broadcast latency percentiles, behind the "latency-stats"       #
+//   feature                                                         #
+//********************************************************************
+
+use std::{sync::Mutex, time::Duration};
+
+// Constants
+const MAX_SAMPLES: usize = 100_000;   // Per stats interval. Any more are dropped rather than eat memory
+
+/// Records how long messages take from being received to being written out to each peer
+#[derive(Default)]
+pub struct Latencies{
+    samples: Mutex<Vec<Duration>>,
+}
+impl Latencies{
+    /// Records the latency of a single delivery
+    pub fn record(&self, latency: Duration){
+        let mut samples = self.samples.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if samples.len() < MAX_SAMPLES {
+            samples.push(latency);
+        }
+    }
+
+    /// Returns a summary of the latencies recorded since the last call, i.e. "p50 1.2 ms, p99 8.4 ms over 120 message(s)"
+    /// and starts over
+    pub fn take_summary(&self) -> String{
+        let mut samples = std::mem::take(&mut *self.samples.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        if samples.is_empty() {
+            return "no messages".to_string();
+        }
+
+        samples.sort_unstable();
+        let percentile = |percent: usize| samples[(samples.len() - 1) * percent / 100].as_secs_f64() * 1000.0;
+        format!("p50 {:.2} ms, p99 {:.2} ms over {} message(s)", percentile(50), percentile(99), samples.len())
+    }
+}
@@ -12,24 +12,45 @@ use futures_util::
     StreamExt
 ;
 use helpers::*;
-use shared::{ChatMessage, HandleError, HandleResult};
+use invites::Invites;
+use shared::{
+    close_frame, enable_keepalive, from_json_limited, validate_room_name, websocket_config, ChatMessage, HandleError, HandleResult, Hello, MessageKind,
+    DEFAULT_ROOM, SYSTEM_USERNAME,
+};
 use simple_logger::SimpleLogger;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{HashMap, HashSet}, net::IpAddr, sync::Arc, time::{Duration, Instant}};
 use time::macros::format_description;
 use tokio::{
     io,
     net::TcpListener,
     select,
     sync::{
-        mpsc::unbounded_channel,
+        watch,
         Mutex,
     },
+    task::JoinSet,
+    time::timeout,
+};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header::AUTHORIZATION, StatusCode},
+        protocol::frame::coding::CloseCode,
+    },
 };
-use tokio_tungstenite::
-    accept_async
-;
 
+mod autoresponses;
+mod commands;
+mod config;
 mod helpers;
+mod invites;
+#[cfg(feature = "latency-stats")]
+mod latency;
+mod redaction;
+
+// Constants
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);  // A client which hasn't upgraded to WebSocket by then is dropped
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
@@ -53,95 +74,399 @@ async fn main() -> io::Result<()> {
         .init()
         .unwrap();
 
+    let config = match config::Config::load() {
+        Ok((config, problems)) => {
+            problems.iter().for_each(|problem| log::warn!("{problem}. Ignoring it"));
+            config
+        }
+        Err(reason) => {
+            log::error!("Could not load the configuration: {reason}");
+            std::process::exit(1);
+        }
+    };
+
     // The main task will handle listening
-    let listening_port = "5050";
-    let listener = TcpListener::bind("0.0.0.0:".to_string() + listening_port).await?;
+    let listener = TcpListener::bind(("0.0.0.0", config.port)).await?;
 
-    log::info!("Listening for incoming connections on port {listening_port}");
+    log::info!("Listening for incoming connections on port {}", config.port);
 
     // Listen for connections and try to upgrade to websocket
     let connection_to_username: UsernameMap = Arc::new(Mutex::new(HashMap::new()));
     let active_websockets: PeerMap = Arc::new(Mutex::new(HashMap::new()));
-    while let Ok((stream, ip)) = listener.accept().await {
-        log::info!("Accepted a tcp connection from {ip}. Attempting to upgrade to WebSocket...");
+    let rooms: RoomMap = Arc::new(Mutex::new(HashMap::new()));
+    let admins: AdminSet = Arc::new(Mutex::new(HashSet::new()));
+    let read_only_rooms: RoomSet = Arc::new(Mutex::new(config.read_only_rooms.iter().cloned().collect()));
+    let departures: DepartureMap = Arc::new(Mutex::new(HashMap::new()));
+    let colors: ColorMap = Arc::new(Mutex::new(HashMap::new()));
+    let last_seen: LastSeenMap = Arc::new(Mutex::new(HashMap::new()));
+    let activity: ActivityMap = Arc::new(Mutex::new(HashMap::new()));
 
-        let ws_stream = match accept_async(stream).await {
-            Ok(result) => result,
-            Err(err) => {
-                log::error!("Could not upgrade connection of ip {ip}: {err}");
-                continue;
-            }
+    let state = Arc::new(ServerState{
+        active_websockets,
+        con_to_username: connection_to_username,
+        rooms,
+        admins,
+        read_only_rooms,
+        departures,
+        colors,
+        last_seen,
+        activity,
+        told_alone: Default::default(),
+        #[cfg(feature = "latency-stats")]
+        latencies: Default::default(),
+        invites: Default::default(),
+        started_at: Instant::now(),
+        auto_response_cooldowns: Default::default(),
+        config: std::sync::RwLock::new(Arc::new(config)),
+    });
+
+    // Keep an eye on slow consumers
+    tokio::spawn(log_stats(Arc::clone(&state)));
+
+    // Let clients know about messages in rooms they are not in
+    tokio::spawn(send_activity(Arc::clone(&state)));
+
+    // Operators may change the configuration of a running server
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(Arc::clone(&state)));
+
+    // Connections are tracked so that they can be closed on shutdown
+    let mut connections = JoinSet::new();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, ip) = select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::error!("Could not accept connection: {err}");
+                    break;
+                }
+            },
+            _ = &mut shutdown => break,
         };
 
-        log::info!("Connection upgraded successfully");
+        log::info!("Accepted a tcp connection from {ip}. Attempting to upgrade to WebSocket...");
+
+        let config = state.config();
+        if let Err(err) = enable_keepalive(&stream, config.keepalive_idle, config.keepalive_interval) {
+            log::warn!("Could not enable TCP keepalive for {ip}: {err}");
+        }
 
         // Handle each connection on a separate task
-        let cloned_active_websockets = Arc::clone(&active_websockets);
-        let cloned_con_to_username = Arc::clone(&connection_to_username);
-        tokio::spawn(async move {
-            // Add websocket to active
-            let (tx, mut rx) = unbounded_channel();
-            cloned_active_websockets.lock().await.insert(ip, tx.clone());
-
-            // Expect a message which should contain the username
+        let cloned_state = Arc::clone(&state);
+        let mut cloned_shutdown_rx = shutdown_rx.clone();
+        connections.spawn(async move {
+            // Upgraded here rather than in the accept loop, so that a client which never finishes the handshake
+            // holds up nobody else, and for a limited time only
+            // The rejection type is dictated by tungstenite
+            #[allow(clippy::result_large_err)]
+            let check_token = |request: &Request, response: Response| authorize(request, response, config.auth_token.as_deref(), &cloned_state.invites, ip.ip());
+            let ws_stream = match timeout(HANDSHAKE_TIMEOUT, accept_hdr_async_with_config(stream, check_token, Some(websocket_config()))).await {
+                Ok(Ok(ws_stream)) => ws_stream,
+                Ok(Err(err)) => {
+                    log::error!("Could not upgrade connection of ip {ip}: {err}");
+                    return;
+                }
+                Err(_) => {
+                    log::error!("Client {ip} did not finish the handshake within {} s. Dropping it", HANDSHAKE_TIMEOUT.as_secs());
+                    return;
+                }
+            };
+
+            log::info!("Connection upgraded successfully");
+
             let (mut write, mut read) = ws_stream.split();
-            let username = match read.next().await {
-                Some(name_result) => match name_result {
-                    Ok(name) => name.to_string(),
-                    Err(err) => {
-                        log::error!("Invalid username message: {err}. Closing connection");
-                        if close_websocket_stream(write, read).await.is_err() {
-                            log::error!("Could not close connection. Aborting connection");
+
+            // Add websocket to active, unless the server is full or this address has too many connections already
+            // Checked under the same lock, so two clients can't both take the last spot
+            // Connections are counted from the active ones, so leaving through any path frees the spot
+            // The limits are read from the same configuration as the token the handshake was checked against
+            let (tx, mut rx) = peer_channel(ip, config.queue_warn_threshold);
+            {
+                let mut actives = cloned_state.active_websockets.lock().await;
+                let from_same_ip = actives.keys().filter(|addr| addr.ip() == ip.ip()).count();
+                let rejection = if actives.len() >= config.max_connections {
+                    Some((CloseCode::Again, "server is full"))
+                } else if config.max_connections_per_ip.is_some_and(|max| from_same_ip >= max) {
+                    Some((CloseCode::Policy, "too many connections from your address"))
+                } else {
+                    None
+                };
+                if let Some((code, reason)) = rejection {
+                    drop(actives);
+                    log::warn!("Turning away client {ip}: {reason}");
+                    if close_websocket_stream(write, read, Some(close_frame(code, reason))).await.is_err() {
+                        log::error!("Could not close connection. Aborting connection");
+                    };
+                    return;
+                }
+                actives.insert(ip, tx.clone());
+            }
+
+            // Expect a message which should contain the username, either as plain text
+            // or as a Hello which can also pick a room
+            // Usernames which are invalid or taken are turned down, and the client may try another one
+            let mut room = DEFAULT_ROOM.to_string();
+            let mut requested_username = None;  // Set if the client was given another username than the one it asked for
+            let username = loop {
+                let hello = match read.next().await {
+                    Some(name_result) => match name_result {
+                        Ok(name) => {
+                            let text = name.to_text().unwrap_or_default();
+                            if !text.starts_with('{') {
+                                Hello{ username: name.to_string(), room: None }
+                            } else {
+                                match from_json_limited::<Hello>(text) {
+                                    Ok(hello) => hello,
+                                    Err(parse_error) => {
+                                        log::error!("Invalid hello from client {ip}: {parse_error}. Closing connection");
+                                        let reason = close_frame(CloseCode::Policy, "invalid hello");
+                                        if close_websocket_stream(write, read, Some(reason)).await.is_err() {
+                                            log::error!("Could not close connection. Aborting connection");
+                                        };
+                                        cloned_state.active_websockets.lock().await.remove(&ip);
+                                        return;
+                                    }
+                                }
+                            }
+                        },
+                        Err(err) => {
+                            log::error!("Invalid username message: {err}. Closing connection");
+                            let reason = close_frame(CloseCode::Policy, "invalid username");
+                            if close_websocket_stream(write, read, Some(reason)).await.is_err() {
+                                log::error!("Could not close connection. Aborting connection");
+                            };
+                            cloned_state.active_websockets.lock().await.remove(&ip);
+                            return;
+                        }
+                    },
+                    None => {
+                        log::error!("Invalid username message. Closing connection");
+                        let reason = close_frame(CloseCode::Policy, "no username received");
+                        if close_websocket_stream(write, read, Some(reason)).await.is_err() {
+                            log::error!("Could not close connection. Aborting all");
                         };
+                        cloned_state.active_websockets.lock().await.remove(&ip);
                         return;
                     }
-                },
-                None => {
-                    log::error!("Invalid username message. Closing connection");
-                    if close_websocket_stream(write, read).await.is_err() {
-                        log::error!("Could not close connection. Aborting all");
+                };
+
+                if let Some(requested_room) = hello.room {
+                    room = requested_room;
+                }
+                if let Err(reason) = validate_room_name(&room) {
+                    log::error!("Client {ip} asked for an invalid room: {reason}. Closing connection");
+                    let reason = close_frame(CloseCode::Policy, &reason);
+                    if close_websocket_stream(write, read, Some(reason)).await.is_err() {
+                        log::error!("Could not close connection. Aborting connection");
                     };
+                    cloned_state.active_websockets.lock().await.remove(&ip);
+                    return;
+                }
+
+                // Taken usernames are either turned down or suffixed, as configured
+                let claimed = match cloned_state.config().max_username_suffix {
+                    Some(max_suffix) => claim_free_username(ip, &hello.username, &room, max_suffix, &cloned_state).await,
+                    None => claim_username(ip, &hello.username, &room, &cloned_state).await.map(|()| hello.username.clone()),
+                };
+                let reason = match claimed {
+                    Ok(username) => {
+                        if username != hello.username {
+                            requested_username = Some(hello.username);
+                        }
+                        break username;
+                    }
+                    Err(reason) => reason,
+                };
+
+                log::info!("Turning down username \"{}\" of client {ip}: {reason}", hello.username);
+                let rejection = ChatMessage::builder()
+                    .from(ip)
+                    .username(SYSTEM_USERNAME.to_string())
+                    .body(reason)
+                    .kind(MessageKind::UsernameRejected)
+                    .room(room.clone())
+                    .build();
+                let delivered = match rejection {
+                    Some(rejection) => tx.send(rejection).is_ok() && handle_received_from_server(ip, &mut rx, &mut write, &mut PendingSend::default(), &cloned_state).await.is_ok(),
+                    None => false,
+                };
+                if !delivered {
+                    log::error!("Could not turn down the username of client {ip}. Aborting connection");
+                    cloned_state.active_websockets.lock().await.remove(&ip);
                     return;
                 }
             };
 
-            // Save the username in the hashmap
-            cloned_con_to_username.lock().await.insert(ip, username.clone());
+            if let Err(reason) = enter_room(ip, &room, &cloned_state.rooms, &cloned_state.config()).await {
+                log::error!("Client {ip} could not enter #{room}: {reason}. Closing connection");
+                cloned_state.con_to_username.lock().await.remove(&ip);
+                cloned_state.active_websockets.lock().await.remove(&ip);
+                let reason = close_frame(CloseCode::Policy, &reason);
+                if close_websocket_stream(write, read, Some(reason)).await.is_err() {
+                    log::error!("Could not close connection. Aborting connection");
+                };
+                return;
+            }
+            reset_alone(&room, &cloned_state).await;
 
-            // Broadcast arrival of current user
-            match ChatMessage::build(ip, "SYSTEM".to_string(), format!("{username} has entered the channel")){
-                Some(entry_message) => broadcast_message(entry_message, &cloned_active_websockets).await,
-                None => log::error!("Could not create user entry broadcast message"),
+            // Let the client in, telling it where it is, and under which name if not the one it asked for
+            match joined_message(ip, &room) {
+                Some(confirmation) => _ = tx.send(confirmation),
+                None => log::error!("Could not create join confirmation for {ip}"),
             }
+            if let Some(requested_username) = requested_username {
+                log::info!("Client {ip} asked for the taken username \"{requested_username}\" and was given \"{username}\"");
+                let rename = ChatMessage::builder()
+                    .from(ip)
+                    .username(SYSTEM_USERNAME.to_string())
+                    .body(username.clone())
+                    .kind(MessageKind::Rename)
+                    .room(room.clone())
+                    .build();
+                let notice = system_message(ip, &room, format!("\"{requested_username}\" is taken, so you joined as {username}"));
+                for message in [rename, notice].into_iter().flatten() {
+                    _ = tx.send(message);
+                }
+            }
+            if !can_post(ip, &room, &cloned_state).await {
+                send_room_access(ip, &room, &cloned_state).await;
+            }
+
+            // Broadcast arrival of current user, unless they only just left
+            if !is_flapping(&username, &room, &cloned_state).await {
+                match system_message(ip, &room, format!("{username} has entered the channel")) {
+                    Some(entry_message) => _ = broadcast_message(entry_message, Audience::Others, &cloned_state).await,
+                    None => log::error!("Could not create user entry broadcast message"),
+                }
+            }
+            send_directory(&cloned_state).await;
 
             // Keep listening for messages from client or from server
-            loop {
+            // Ends with how the client left, if it is to be announced
+            let mut pending_send = PendingSend::default();  // Survives the write branch being dropped, see handle_received_from_server
+            let farewell = loop {
                 // Select between receiveing from the server and broadcasting messages received from the websocket
+                // Returning from either branch drops the other, so a send still in flight to a
+                // dead peer is cancelled as soon as the read half reports the disconnect. Messages from the client
+                // are only handled once read, outside of the race, so they are never dropped halfway
+                // Fairness: select! is left unbiased on purpose, so every iteration polls the branches
+                // in a random order. A flood of broadcasts can then only delay the client's own
+                // messages by a few iterations rather than starve them, and the other way around.
+                // Adding "biased;" would let whichever branch comes first win every time both are ready
                 select! {
-                    handle_result = handle_received_from_client(&cloned_active_websockets, &cloned_con_to_username, &mut read, ip) => {
-                        match handle_result{
-                            Ok(HandleResult::ResponseSuccessful) => log::debug!("Response successfully sent to {} ({ip})", cloned_con_to_username.lock().await.get(&ip).unwrap_or(&"Unknown".to_string())),
+                    _ = cloned_shutdown_rx.changed() => {
+                        log::debug!("Closing connection with client {ip} for shutdown");
+                        let reason = close_frame(CloseCode::Away, "server is shutting down");
+                        if close_websocket_stream(write, read, Some(reason)).await.is_err() {
+                            log::error!("Could not close connection with client {ip}");
+                        }
+                        break None;
+                    },
+                    _ = tx.disconnect_requested() => {
+                        // Usually a client which stopped reading, so the close may well time out. It is let go of either way
+                        log::debug!("Closing connection with client {ip} on an admin's request");
+                        let reason = close_frame(CloseCode::Policy, "disconnected by an admin");
+                        if close_websocket_stream(write, read, Some(reason)).await.is_err() {
+                            log::error!("Could not close connection with client {ip}");
+                        }
+                        break Some("was disconnected by an admin");
+                    },
+                    received = read.next() => {
+                        match handle_received_from_client(&cloned_state, received, ip).await {
+                            Ok(HandleResult::ResponseSuccessful) => log::debug!("Response successfully sent to {} ({ip})", cloned_state.con_to_username.lock().await.get(&ip).unwrap_or(&"Unknown".to_string())),
                             Err(HandleError::MalformedMessage) => log::debug!("Malformed message received from client {ip}. Ignoring"),
                             Err(HandleError::ConnectionDropped) => {
                                 log::debug!("Connection with client {ip} interrupted.");
-                                return;
+                                break Some("has lost connection");
                             },
                             Err(HandleError::UnkownClient) => log::error!("Unkown client"),
+                            Err(HandleError::Disconnected(reason)) => {
+                                log::debug!("Client {ip} disconnected: {reason}");
+                                break Some("has left the channel");
+                            },
+                            Err(HandleError::ServerFull) => log::error!("Unexpected server full error for client {ip}"),
+                            Err(HandleError::Interrupted(_)) => log::error!("Unexpected interrupted error for client {ip}"),
                         }
                     },
-                    handle_result = handle_received_from_server(&mut rx, &mut write) => match handle_result {
-                        Ok(HandleResult::ResponseSuccessful) => log::debug!("Response successfully sent to {} ({ip})", cloned_con_to_username.lock().await.get(&ip).unwrap_or(&"Unknown".to_string())),
+                    handle_result = handle_received_from_server(ip, &mut rx, &mut write, &mut pending_send, &cloned_state) => match handle_result {
+                        Ok(HandleResult::ResponseSuccessful) => log::debug!("Response successfully sent to {} ({ip})", cloned_state.con_to_username.lock().await.get(&ip).unwrap_or(&"Unknown".to_string())),
                         Err(HandleError::MalformedMessage) => log::debug!("Malformed message received from client {ip}. Ignoring"),
+                        // A peer which can't be written to is gone, even if it still writes
                         Err(HandleError::ConnectionDropped) => {
                             log::debug!("Connection with client {ip} interrupted.");
-                            return;
+                            break Some("has lost connection");
                         },
                         Err(HandleError::UnkownClient) => log::error!("Unkown client"),
+                        Err(HandleError::Disconnected(reason)) => {
+                            log::debug!("Client {ip} disconnected: {reason}");
+                            break None;
+                        },
+                        Err(HandleError::ServerFull) => log::error!("Unexpected server full error for client {ip}"),
+                        Err(HandleError::Interrupted(_)) => log::error!("Unexpected interrupted error for client {ip}"),
                     }
                 }
+            };
+
+            // Forget about the client, so it doesn't linger in its room
+            let username = cloned_state.con_to_username.lock().await.remove(&ip).unwrap_or(username);
+            let room = current_room(ip, &cloned_state.rooms).await;
+            cloned_state.active_websockets.lock().await.remove(&ip);
+            cloned_state.admins.lock().await.remove(&ip);
+            cloned_state.colors.lock().await.remove(&ip);
+            cloned_state.told_alone.lock().await.remove(&ip);
+            remember_last_seen(&username, &cloned_state.last_seen).await;
+            leave_room(ip, &cloned_state.rooms, &cloned_state.config()).await;
+            send_directory(&cloned_state).await;
+
+            // Announced apart, as it may be held back for a while
+            if let Some(farewell) = farewell {
+                tokio::spawn(announce_departure(ip, username, room, farewell, Arc::clone(&cloned_state)));
             }
         });
+
+        // Forget about connections which have already ended
+        while connections.try_join_next().is_some() {}
+    }
+
+    // Ask every connection to close, giving them some time to do so
+    log::info!("Shutting down. Closing {} connection(s)", connections.len());
+    _ = shutdown_tx.send(true);
+    let shutdown_grace = state.config().shutdown_grace;
+    let all_closed = timeout(shutdown_grace, async {
+        while connections.join_next().await.is_some() {}
+    });
+    if all_closed.await.is_err() {
+        log::warn!("Some connections did not close within {} s. Dropping them", shutdown_grace.as_secs());
+        connections.shutdown().await;
     }
 
     Ok(())
 }
+
+/// Lets the handshake through if "request" carries "Authorization: Bearer <token>", or instead an invite token
+/// which is unused or was used by "ip" before, or if no token is required
+/// Refuses it with a 401 otherwise
+#[allow(clippy::result_large_err)]
+fn authorize(request: &Request, response: Response, token: Option<&str>, invites: &Invites, ip: IpAddr) -> Result<Response, ErrorResponse> {
+    let Some(token) = token else {
+        return Ok(response);
+    };
+
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if given.is_some_and(|given| secrets_match(given, token) || invites.redeem(given, ip)) {
+        return Ok(response);
+    }
+
+    let reason = if given.is_none() {"missing token"} else {"invalid token"};
+    let mut rejection = ErrorResponse::new(Some(reason.to_string()));
+    *rejection.status_mut() = StatusCode::UNAUTHORIZED;
+    Err(rejection)
+}
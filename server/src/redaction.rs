@@ -0,0 +1,77 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Replaces unwanted words in messages with asterisks              #
+//********************************************************************
+
+// Constants
+const REDACTION_CHAR: char = '*';
+
+/// Replaces every whole word of "body" found in "words" with asterisks, ignoring case
+/// Only whole words are matched, so "class" is left alone when "ass" is redacted
+/// "words" are expected in lowercase
+pub fn redact(body: &str, words: &[String]) -> String {
+    if words.is_empty() {
+        return body.to_string();
+    }
+
+    let mut redacted = String::with_capacity(body.len());
+    let mut word = String::new();
+    for char in body.chars().chain(std::iter::once(' ')) {
+        if char.is_alphanumeric() {
+            word.push(char);
+            continue;
+        }
+
+        // A word just ended
+        if words.contains(&word.to_lowercase()) {
+            redacted.extend(word.chars().map(|_| REDACTION_CHAR));
+        } else {
+            redacted.push_str(&word);
+        }
+        word.clear();
+        redacted.push(char);
+    }
+
+    // Drop the space added to end the last word
+    redacted.pop();
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words() -> Vec<String> {
+        vec!["ass".to_string(), "école".to_string()]
+    }
+
+    #[test]
+    fn only_whole_words_are_redacted() {
+        assert_eq!(redact("a class act", &words()), "a class act");
+        assert_eq!(redact("assess this", &words()), "assess this");
+        assert_eq!(redact("pain in the ass", &words()), "pain in the ***");
+    }
+
+    #[test]
+    fn case_and_punctuation_around_words_are_kept() {
+        assert_eq!(redact("Ass! What an ASS, really", &words()), "***! What an ***, really");
+        assert_eq!(redact("(ass)\nass", &words()), "(***)\n***");
+    }
+
+    #[test]
+    fn non_ascii_words_are_redacted_whole() {
+        assert_eq!(redact("L'ÉCOLE est fermée", &words()), "L'***** est fermée");
+        assert_eq!(redact("écoles", &words()), "écoles");
+    }
+
+    #[test]
+    fn nothing_to_redact() {
+        assert_eq!(redact("ass", &[]), "ass");
+        assert_eq!(redact("", &words()), "");
+    }
+}
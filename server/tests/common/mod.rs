@@ -0,0 +1,60 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Starting the server and joining it, for the integration tests   #
+//********************************************************************
+
+use std::{net::TcpListener, process::{Child, Command, Stdio}, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, time::sleep};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The server binary, killed once dropped
+pub struct Server(Child);
+impl Drop for Server{
+    fn drop(&mut self){
+        _ = self.0.kill();
+        _ = self.0.wait();
+    }
+}
+
+/// Starts the server on a free port, returning it along with that port
+pub fn start_server() -> (Server, u16) {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .env("PORT", port.to_string())
+        .env("RUST_LOG", "error")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    (Server(child), port)
+}
+
+/// Connects as "username", once the server is up, and waits to be let in
+pub async fn join(port: u16, username: &str) -> Client {
+    let mut client = None;
+    for _ in 0..50 {
+        if let Ok((connected, _)) = connect_async(format!("ws://127.0.0.1:{port}")).await {
+            client = Some(connected);
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let mut client = client.expect("the server never came up");
+
+    client.send(Message::Text(username.into())).await.unwrap();
+    while let Some(Ok(message)) = client.next().await {
+        if message.to_text().unwrap().contains("\"Joined\"") {
+            break;
+        }
+    }
+    client
+}
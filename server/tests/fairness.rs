@@ -0,0 +1,69 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Checks that a client's own messages still get through while     #
+//   the server floods it with broadcasts                            #
+//********************************************************************
+
+use std::time::Duration;
+
+use common::{join, start_server};
+use futures_util::{SinkExt, StreamExt};
+use shared::ClientMessage;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+
+mod common;
+
+const FLOOD_SIZE: usize = 5_000;
+
+/// Whether "message" is the chat message "body" sent by "username"
+fn is_from(message: &Message, username: &str, body: &str) -> bool {
+    let Ok(text) = message.to_text() else {
+        return false;
+    };
+    serde_json::from_str::<ClientMessage>(text).is_ok_and(|client_message| client_message.get_username() == username && client_message.get_message() == body)
+}
+
+#[tokio::test]
+async fn own_messages_get_through_a_flood() {
+    let (_server, port) = start_server();
+    let mut flooder = join(port, "flooder").await;
+    let mut victim = join(port, "victim").await;
+    let mut watcher = join(port, "watcher").await;
+
+    let flood = tokio::spawn(async move {
+        for index in 0..FLOOD_SIZE {
+            flooder.send(Message::Text(format!("flood {index} {}", "x".repeat(500)).into())).await.unwrap();
+        }
+        flooder
+    });
+
+    // The victim speaks up once it is well into the flood, reading it all along
+    let mut flooded = 0;
+    while flooded < FLOOD_SIZE / 10 {
+        let message = victim.next().await.unwrap().unwrap();
+        if message.to_text().unwrap().contains("flood ") {
+            flooded += 1;
+        }
+    }
+    victim.send(Message::Text("still here".into())).await.unwrap();
+    let drain = tokio::spawn(async move { while victim.next().await.is_some() {} });
+
+    let got_through = timeout(Duration::from_secs(20), async {
+        while let Some(Ok(message)) = watcher.next().await {
+            if is_from(&message, "victim", "still here") {
+                return true;
+            }
+        }
+        false
+    });
+    assert!(got_through.await.unwrap_or(false), "the victim's message never reached the watcher");
+
+    drain.abort();
+    flood.abort();
+}
@@ -0,0 +1,29 @@
+//********************************************************************
+// Author: Lauro França (oPisiti)                                    #
+// Contact:                                                          #
+//   github: oPisiti                                                 #
+//   Email: contact@opisiti.com                                      #
+// Date: 2025                                                        #
+// Description:                                                      #
+//   Checks that a client stuck in the handshake doesn't hold up     #
+//   anyone else                                                     #
+//********************************************************************
+
+use std::time::Duration;
+
+use common::{join, start_server};
+use tokio::{net::TcpStream, time::timeout};
+
+mod common;
+
+#[tokio::test]
+async fn stalled_handshake_does_not_block_others() {
+    let (_server, port) = start_server();
+    let _first = join(port, "first").await;
+
+    // Connects, but never sends the HTTP upgrade request
+    let _stalled = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+
+    let second = timeout(Duration::from_secs(5), join(port, "second")).await;
+    assert!(second.is_ok(), "a stalled handshake held up the next client");
+}
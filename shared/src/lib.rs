@@ -10,30 +10,250 @@
 
 use futures_util::stream::{SplitSink, SplitStream};
 use tokio::net::TcpStream;
-use std::{fmt, net::SocketAddr, time::Instant};
-use serde::{Deserialize, Serialize};
-use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use std::{fmt, io, net::SocketAddr, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use serde::{de::{DeserializeOwned, IgnoredAny}, Deserialize, Deserializer, Serialize};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
 
 // Aliases
 pub type WSWrite = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
 pub type WSRead = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
 
+// Constants
+pub const SYSTEM_USERNAME: &str = "SYSTEM";
+pub const CLIENT_USERNAME: &str = "You";
+pub const MAX_USERNAME_LENGTH: usize = 32;
+pub const DEFAULT_ROOM: &str = "general";
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+pub const ROOM_READ_ONLY: &str = "read-only";
+pub const ROOM_WRITABLE: &str = "writable";
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;     // In bytes, for both websocket messages and JSON payloads
+pub const MAX_JSON_DEPTH: usize = 16;              // Our own payloads are only ever a couple of levels deep
+pub const INVITE_LINK_PREFIX: &str = "chatey://join?";
+pub const MIN_COLOR_LUMINANCE: f32 = 0.25;         // Darker colors are unreadable on the black background
+
+/// The named colors users may pick, all readable on the black background
+pub const COLOR_NAMES: [&str; 14] = [
+    "red", "green", "yellow", "blue", "magenta", "cyan", "gray", "white",
+    "lightred", "lightgreen", "lightyellow", "lightblue", "lightmagenta", "lightcyan",
+];
+
+/// What a message represents, so that clients know how to render or act on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MessageKind{
+    /// A message typed by a user
+    #[default]
+    Chat,
+    /// A notice generated by the server itself
+    System,
+    /// Tells the receiving client that its username is now the message body
+    Rename,
+    /// Tells the receiving client that it is now in the room named by the message body
+    Joined,
+    /// Tells the receiving client that the username it asked for was turned down, for the reason in the body
+    UsernameRejected,
+    /// Tells the receiving client whether it may post in its current room. The body is either
+    /// ROOM_READ_ONLY or ROOM_WRITABLE
+    RoomAccess,
+    /// Tells the receiving client who is in its room. The body is a JSON array of usernames
+    Roster,
+    /// Tells the receiving client which rooms exist. The body is a JSON array of room names
+    RoomList,
+    /// Tells the receiving client to clear its view of the room named by the message body
+    ClearHistory,
+    /// Tells the receiving client which colors users picked. The body is a JSON object of usernames to colors
+    Colors,
+    /// A table posted by a user with "/table". The body is a JSON Table
+    Table,
+    /// Answers "/invite". The body is a JSON Invite, whose server is left for the client to fill in
+    Invite,
+    /// Tells the receiving client how many messages were posted in each room since the server started.
+    /// The body is a JSON object of room names to counts
+    RoomActivity,
+}
+impl MessageKind{
+    /// Whether messages of this kind belong to the room they were sent to, making no sense in any other
+    /// Those still on their way when the client changes rooms are dropped rather than shown in the new one
+    pub fn is_room_bound(self) -> bool{
+        matches!(self, Self::Chat | Self::System | Self::Table | Self::Roster | Self::ClearHistory)
+    }
+}
+
+/// The first message sent by a client, introducing itself
+/// Clients may also just send their username as plain text, joining the default room
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello{
+    pub username: String,
+
+    #[serde(default)]
+    pub room: Option<String>,
+}
+
+/// Structured data posted with "/table", such as a bot's query results
+/// Every row has exactly as many cells as there are headers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Table{
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+impl Table{
+    /// Parses a table from "json", i.e. {"headers": ["name", "score"], "rows": [["bob", "3"]]}
+    /// Returns the reason if it is not valid JSON or any row doesn't match the headers
+    pub fn parse(json: &str) -> Result<Self, String>{
+        let table: Self = from_json_limited(json)?;
+        if table.headers.is_empty() {
+            return Err("a table needs at least one header".to_string());
+        }
+        if let Some(index) = table.rows.iter().position(|row| row.len() != table.headers.len()) {
+            return Err(format!("row {} has {} cell(s), but there are {} header(s)", index + 1, table.rows[index].len(), table.headers.len()));
+        }
+        Ok(table)
+    }
+
+    /// Renders the table as plain text, one row per line with cells separated by " | "
+    /// For wherever the table can't be drawn as such
+    pub fn to_text(&self) -> String{
+        std::iter::once(&self.headers)
+            .chain(&self.rows)
+            .map(|row| row.join(" | "))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// How a command is used and what it does, as listed by "/help"
+pub struct CommandHelp{
+    /// i.e. "/join"
+    pub name: &'static str,
+    /// i.e. "<room>". Empty if it takes none
+    pub args: &'static str,
+    pub description: &'static str,
+}
+
+/// Every command handled by the server
+pub const SERVER_COMMANDS: [CommandHelp; 15] = [
+    CommandHelp{ name: "/rename", args: "<username>", description: "changes your username for the rest of the session" },
+    CommandHelp{ name: "/join", args: "<room>", description: "leaves the current room for another one, creating it if needed" },
+    CommandHelp{ name: "/admin", args: "<password>", description: "grants admin rights for the rest of the session" },
+    CommandHelp{ name: "/crosspost", args: "<room1,room2,...> <message>", description: "admins only. Sends the message to every listed room" },
+    CommandHelp{ name: "/readonly", args: "", description: "admins only. Makes the current room read-only, or writable again" },
+    CommandHelp{ name: "/color", args: "[color]", description: "makes everyone see your name in that color, or the default one without it" },
+    CommandHelp{ name: "/clearall", args: "", description: "admins only. Clears the history of the current room for everyone in it" },
+    CommandHelp{ name: "/table", args: "<json>", description: "posts a table, i.e. {\"headers\": [\"name\"], \"rows\": [[\"bob\"]]}" },
+    CommandHelp{ name: "/ephemeral", args: "<seconds> <message>", description: "sends a message which disappears that many seconds after being received" },
+    CommandHelp{ name: "/invite", args: "", description: "shows a link others may join your current room with" },
+    CommandHelp{ name: "/seen", args: "<username>", description: "tells whether that user is online, or when they were last seen" },
+    CommandHelp{ name: "/uptime", args: "", description: "tells how long the server has been up, and how many clients are connected" },
+    CommandHelp{ name: "/move", args: "<address or username> <room>", description: "admins only. Moves that user into another room" },
+    CommandHelp{ name: "/connections", args: "", description: "admins only. Lists every connection" },
+    CommandHelp{ name: "/disconnect", args: "<address or username>", description: "admins only. Closes that connection" },
+];
+
+/// Where to join someone, shared as a "chatey://join?server=...&room=...[&token=...]" link made with "/invite"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invite{
+    /// Empty when sent by the server, which doesn't know the address clients reach it at
+    #[serde(default)]
+    pub server: String,
+    pub room: String,
+    /// A one-time token, only given out by servers which require one
+    #[serde(default)]
+    pub token: Option<String>,
+}
+impl Invite{
+    /// Formats the invite as a link, percent-encoding every value
+    pub fn to_link(&self) -> String{
+        let mut link = format!("{INVITE_LINK_PREFIX}server={}&room={}", percent_encode(&self.server), percent_encode(&self.room));
+        if let Some(token) = &self.token {
+            link.push_str(&format!("&token={}", percent_encode(token)));
+        }
+        link
+    }
+
+    /// Parses a link made by "to_link"
+    /// Returns the reason if it isn't an invite link, or the server or the room are missing
+    pub fn parse_link(link: &str) -> Result<Self, String>{
+        let query = link
+            .trim()
+            .strip_prefix(INVITE_LINK_PREFIX)
+            .ok_or(format!("an invite link starts with \"{INVITE_LINK_PREFIX}\""))?;
+
+        let (mut server, mut room, mut token) = (None, None, None);
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or(format!("\"{pair}\" is not a key=value pair"))?;
+            let value = percent_decode(value).ok_or(format!("the value of \"{key}\" is not properly encoded"))?;
+            match key {
+                "server" => server = Some(value),
+                "room" => room = Some(value),
+                "token" => token = Some(value),
+                _ => return Err(format!("unknown field \"{key}\"")),
+            }
+        }
+
+        Ok(Self{
+            server: server.filter(|server| !server.is_empty()).ok_or("the server is missing")?,
+            room: room.filter(|room| !room.is_empty()).ok_or("the room is missing")?,
+            token: token.filter(|token| !token.is_empty()),
+        })
+    }
+}
+
+/// Escapes every byte of "value" but letters, digits and "-._~" as "%XX"
+fn percent_encode(value: &str) -> String{
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => char::from(byte).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Undoes "percent_encode". Returns None on a broken escape or if the result is not UTF-8
+fn percent_decode(value: &str) -> Option<String>{
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
 #[derive(Clone)]
 pub struct ChatMessage{
     from_addr: SocketAddr,
     from_username: String,
     timestamp: Instant,
-    message: String
+    message: String,
+    kind: MessageKind,
+    room: String,
+    expires_in: Option<Duration>,
 }
 impl ChatMessage {
+    /// Returns a builder for a ChatMessage
+    pub fn builder() -> ChatMessageBuilder{
+        ChatMessageBuilder::default()
+    }
+
     /// Attemps to build a ChatMessage instance
     pub fn build(socket: SocketAddr, username: String, message: String) -> Option<Self>{
-        Some(Self{
-            from_addr: socket,
-            from_username: username,
-            timestamp: Instant::now(),
-            message
-        })
+        Self::builder()
+            .from(socket)
+            .username(username)
+            .body(message)
+            .build()
     }
     
     /// A getter method for the socket address
@@ -51,6 +271,31 @@ impl ChatMessage {
         self.message.clone()
     }
 
+    /// A getter method for the message kind
+    pub fn get_kind(&self) -> MessageKind{
+        self.kind
+    }
+
+    /// A getter method for the room the message was sent to
+    pub fn get_room(&self) -> String{
+        self.room.clone()
+    }
+
+    /// A getter method for the time the message was created at
+    pub fn get_timestamp(&self) -> Instant{
+        self.timestamp
+    }
+
+    /// A getter method for how long the message is shown for, if it disappears at all
+    pub fn get_expires_in(&self) -> Option<Duration>{
+        self.expires_in
+    }
+
+    /// Returns the message as delivered into "room", i.e. one of the rooms it was cross-posted to
+    pub fn in_room(self, room: String) -> Self{
+        Self{ room, ..self }
+    }
+
     /// Creates a client ChatMessage from a ClientMessage, overriding
     /// the timestamp and username (based on SocketAddr)
     pub fn from(msg: ClientMessage, from_addr: SocketAddr, from_username: String) -> Self {
@@ -59,30 +304,165 @@ impl ChatMessage {
             from_addr,
             from_username,
             message: msg.input_message,
+            kind: msg.kind,
+            room: DEFAULT_ROOM.to_string(),
+            expires_in: msg.expires_in_secs.map(Duration::from_secs),
         }
     }
 }
+/// Shows every field, i.e. `"bob" (127.0.0.1:5000): "hi there" (Chat in #general, 3 s ago)`, followed by
+/// `, disappears after 10 s` for messages which do
+/// The username and body are quoted and escaped, so that it always fits in a single line and neither can pass for
+/// the rest of it
 impl fmt::Display for ChatMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} : {}, {} s ago", self.from_addr, self.message, self.timestamp.elapsed().as_secs())
+        write!(
+            f,
+            "{:?} ({}): {:?} ({:?} in #{}, {} s ago",
+            self.from_username, self.from_addr, self.message, self.kind, self.room, self.timestamp.elapsed().as_secs()
+        )?;
+        if let Some(expires_in) = self.expires_in {
+            write!(f, ", disappears after {} s", expires_in.as_secs_f64())?;
+        }
+        write!(f, ")")
     }
 }
 
+/// Builds a ChatMessage field by field
+/// The sender address, username and body are all required
+#[derive(Default)]
+pub struct ChatMessageBuilder{
+    from_addr: Option<SocketAddr>,
+    from_username: Option<String>,
+    message: Option<String>,
+    kind: MessageKind,
+    room: Option<String>,
+    expires_in: Option<Duration>,
+}
+impl ChatMessageBuilder{
+    /// Sets the address of the sender
+    pub fn from(mut self, addr: SocketAddr) -> Self{
+        self.from_addr = Some(addr);
+        self
+    }
+
+    /// Sets the username of the sender
+    pub fn username(mut self, username: String) -> Self{
+        self.from_username = Some(username);
+        self
+    }
+
+    /// Sets the message body
+    pub fn body(mut self, message: String) -> Self{
+        self.message = Some(message);
+        self
+    }
+
+    /// Sets the message kind. Defaults to MessageKind::Chat
+    pub fn kind(mut self, kind: MessageKind) -> Self{
+        self.kind = kind;
+        self
+    }
+
+    /// Sets the room the message is sent to. Defaults to DEFAULT_ROOM
+    pub fn room(mut self, room: String) -> Self{
+        self.room = Some(room);
+        self
+    }
+
+    /// Makes the message disappear from clients "expires_in" after they receive it. Kept by default
+    pub fn expires_in(mut self, expires_in: Duration) -> Self{
+        self.expires_in = Some(expires_in);
+        self
+    }
+
+    /// Attempts to build the ChatMessage, timestamped now
+    /// Returns None if a required field is missing
+    pub fn build(self) -> Option<ChatMessage>{
+        Some(ChatMessage{
+            from_addr: self.from_addr?,
+            from_username: self.from_username?,
+            timestamp: Instant::now(),
+            message: self.message?,
+            kind: self.kind,
+            room: self.room.unwrap_or(DEFAULT_ROOM.to_string()),
+            expires_in: self.expires_in,
+        })
+    }
+}
+
+/// Reads a timestamp written by "serde_millis", as milliseconds since the Unix epoch
+/// Falls back to now for anything else, or for times an Instant can't hold, rather than failing the whole message
+fn timestamp_or_now<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error>{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Millis{
+        Valid(u64),
+        Other(IgnoredAny),
+    }
+
+    let now = Instant::now();
+    let Millis::Valid(millis) = Millis::deserialize(deserializer)? else {
+        return Ok(now);
+    };
+
+    let system_now = SystemTime::now();
+    let timestamp = UNIX_EPOCH.checked_add(Duration::from_millis(millis)).and_then(|time| match time.duration_since(system_now) {
+        Ok(ahead) => now.checked_add(ahead),
+        Err(behind) => now.checked_sub(behind.duration()),
+    });
+    Ok(timestamp.unwrap_or(now))
+}
+
 /// Created when the user finished inputting a message
-#[derive(Debug, Serialize, Deserialize)]
+/// Every field is serialized, so it survives a trip through JSON, the timestamp to the millisecond
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientMessage{
     input_message: String,
     from_username: String,
 
-    #[serde(with = "serde_millis")]
+    /// Written by "serde_millis", but anything unexpected is read as now, see "timestamp_or_now"
+    #[serde(serialize_with = "serde_millis::serialize", deserialize_with = "timestamp_or_now", default = "Instant::now")]
     timestamp: Instant,
+
+    #[serde(default)]
+    kind: MessageKind,
+
+    /// How many seconds after being received the message disappears, if it does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_in_secs: Option<u64>,
+
+    /// The room the message was delivered into. Only set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room: Option<String>,
+
+    /// Expiry counts from here rather than from "timestamp", so the clocks of both ends never need to agree
+    #[serde(skip, default = "Instant::now")]
+    received_at: Instant,
 }
 impl ClientMessage{
+    /// Returns a builder for a ClientMessage
+    pub fn builder() -> ClientMessageBuilder{
+        ClientMessageBuilder::default()
+    }
+
     pub fn new(from_username: String, input_message: String) -> Self{
         Self{
             input_message,
             from_username,
-            timestamp: Instant::now()
+            timestamp: Instant::now(),
+            kind: MessageKind::Chat,
+            expires_in_secs: None,
+            room: None,
+            received_at: Instant::now(),
+        }
+    }
+
+    /// Creates a SYSTEM message
+    pub fn system(input_message: String) -> Self{
+        Self{
+            kind: MessageKind::System,
+            ..Self::new(SYSTEM_USERNAME.to_string(), input_message)
         }
     }
 
@@ -96,12 +476,45 @@ impl ClientMessage{
         self.from_username.clone()
     }
 
+    /// A getter method for the message kind
+    pub fn get_kind(&self) -> MessageKind{
+        self.kind
+    }
+
+    /// A getter method for the timestamp
+    pub fn get_timestamp(&self) -> Instant{
+        self.timestamp
+    }
+
+    /// A getter method for the room the message was delivered into, if the server said
+    pub fn get_room(&self) -> Option<String>{
+        self.room.clone()
+    }
+
+    /// How long until the message disappears, if it does. Zero once it is due
+    pub fn time_left(&self) -> Option<Duration>{
+        let expires_in = Duration::from_secs(self.expires_in_secs?);
+        Some(expires_in.saturating_sub(self.received_at.elapsed()))
+    }
+
+    /// Whether the message is due to disappear
+    pub fn is_expired(&self) -> bool{
+        self.time_left().is_some_and(|time_left| time_left.is_zero())
+    }
+
     /// Creates a ClientMessage from a ChatMessage
     pub fn from(input: ChatMessage) -> Self{
-        Self::new(input.get_username(), input.get_message())
+        Self{
+            kind: input.get_kind(),
+            // Rounded up, so that nothing ever disappears sooner than asked
+            expires_in_secs: input.get_expires_in().map(|expires_in| expires_in.as_secs() + u64::from(expires_in.subsec_nanos() > 0)),
+            room: Some(input.get_room()),
+            ..Self::new(input.get_username(), input.get_message())
+        }
     }
 
     /// Returns a pretty string containing user and timestamp
+    /// Only meant to be shown, as the username may contain anything: the getters are there for everything else
     pub fn get_metadata(&self) -> String{
         let time_lengths = [
             (60.0, "s"),
@@ -129,6 +542,61 @@ impl ClientMessage{
     }
 }
 
+/// Shows every field sent along, i.e. `"bob": "hi there" (Chat in #general, 3 s ago)`, leaving out the room if the
+/// server didn't say, and followed by `, disappears after 10 s` for messages which do
+/// The username and body are quoted and escaped, so that it always fits in a single line and neither can pass for
+/// the rest of it
+impl fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {:?} ({:?}", self.from_username, self.input_message, self.kind)?;
+        if let Some(room) = &self.room {
+            write!(f, " in #{room}")?;
+        }
+        write!(f, ", {} s ago", self.timestamp.elapsed().as_secs())?;
+        if let Some(expires_in_secs) = self.expires_in_secs {
+            write!(f, ", disappears after {expires_in_secs} s")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Builds a ClientMessage field by field
+/// The username and body are both required
+#[derive(Default)]
+pub struct ClientMessageBuilder{
+    from_username: Option<String>,
+    input_message: Option<String>,
+    kind: MessageKind,
+}
+impl ClientMessageBuilder{
+    /// Sets the username of the sender
+    pub fn username(mut self, username: String) -> Self{
+        self.from_username = Some(username);
+        self
+    }
+
+    /// Sets the message body
+    pub fn body(mut self, message: String) -> Self{
+        self.input_message = Some(message);
+        self
+    }
+
+    /// Sets the message kind. Defaults to MessageKind::Chat
+    pub fn kind(mut self, kind: MessageKind) -> Self{
+        self.kind = kind;
+        self
+    }
+
+    /// Attempts to build the ClientMessage, timestamped now
+    /// Returns None if a required field is missing
+    pub fn build(self) -> Option<ClientMessage>{
+        Some(ClientMessage{
+            kind: self.kind,
+            ..ClientMessage::new(self.from_username?, self.input_message?)
+        })
+    }
+}
+
 /// Indicates a sucessful handling
 pub enum HandleResult{
     ResponseSuccessful,
@@ -138,6 +606,275 @@ pub enum HandleResult{
 pub enum HandleError{
     ConnectionDropped,
     MalformedMessage,
-    UnkownClient
+    UnkownClient,
+    /// The connection was closed by the peer, with a human-readable reason
+    Disconnected(String),
+    /// The connection was closed by the server for a passing reason, i.e. a restart, given as human-readable text
+    /// Worth connecting again, unlike "Disconnected"
+    Interrupted(String),
+    /// The server refused the connection as it can't take any more clients for now
+    ServerFull,
+}
+
+/// Checks whether "username" may be used in the chat, returning the reason if not
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if username.trim().is_empty() {
+        return Err("username cannot be empty".to_string());
+    }
+    if username.chars().count() > MAX_USERNAME_LENGTH {
+        return Err(format!("username cannot be longer than {MAX_USERNAME_LENGTH} characters"));
+    }
+    if username == SYSTEM_USERNAME || username == CLIENT_USERNAME {
+        return Err(format!("username \"{username}\" is reserved"));
+    }
+
+    // Line breaks and terminal escapes would mess up everywhere the username is shown
+    if username.chars().any(char::is_control) {
+        return Err("username cannot contain control characters".to_string());
+    }
+
+    Ok(())
 }
 
+/// Checks whether "room" is a valid room name, returning the reason if not
+/// Room names are made of letters, digits, '-' and '_'
+pub fn validate_room_name(room: &str) -> Result<(), String> {
+    if room.is_empty() {
+        return Err("room name cannot be empty".to_string());
+    }
+    if room.chars().count() > MAX_ROOM_NAME_LENGTH {
+        return Err(format!("room name cannot be longer than {MAX_ROOM_NAME_LENGTH} characters"));
+    }
+    if !room.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return Err("room name may only contain letters, digits, '-' and '_'".to_string());
+    }
+
+    Ok(())
+}
+
+/// Checks whether "color" may be picked by a user, returning it in its canonical form if so
+/// Colors are either one of COLOR_NAMES or "#rrggbb", as long as they are bright enough to be read
+pub fn validate_color(color: &str) -> Result<String, String> {
+    let color = color.trim().to_lowercase().replace(['-', '_', ' '], "");
+    if COLOR_NAMES.contains(&color.as_str()) {
+        return Ok(color);
+    }
+
+    let Some(hex) = color.strip_prefix('#').filter(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit())) else {
+        return Err(format!("color must be \"#rrggbb\" or one of {}", COLOR_NAMES.join(", ")));
+    };
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).unwrap_or_default() as f32 / 255.0;
+    let (red, green, blue) = (channel(0), channel(2), channel(4));
+
+    if 0.2126 * red + 0.7152 * green + 0.0722 * blue < MIN_COLOR_LUMINANCE {
+        return Err(format!("\"{color}\" is too dark to be read"));
+    }
+    Ok(color)
+}
+
+/// Builds a Close frame telling the peer why it is being disconnected
+pub fn close_frame(code: CloseCode, reason: &str) -> CloseFrame {
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+
+/// The websocket settings used on both ends, refusing messages over MAX_MESSAGE_SIZE
+pub fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig::default()
+        .max_message_size(Some(MAX_MESSAGE_SIZE))
+        .max_frame_size(Some(MAX_MESSAGE_SIZE))
+}
+
+/// Enables TCP keepalive on "stream", so that the kernel eventually reaps peers which silently went away
+/// Probes start after "idle" without traffic and are repeated every "interval"
+pub fn enable_keepalive(stream: &TcpStream, idle: Duration, interval: Duration) -> io::Result<()> {
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(idle)
+        .with_interval(interval);
+    socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// Deserializes "json", refusing payloads which are over MAX_MESSAGE_SIZE or nested deeper than MAX_JSON_DEPTH
+/// Both are checked before parsing, so hostile payloads are rejected cheaply
+pub fn from_json_limited<T: DeserializeOwned>(json: &str) -> Result<T, String> {
+    if json.len() > MAX_MESSAGE_SIZE {
+        return Err(format!("payload of {} bytes is over the limit of {MAX_MESSAGE_SIZE}", json.len()));
+    }
+    if exceeds_json_depth(json, MAX_JSON_DEPTH) {
+        return Err(format!("payload is nested deeper than {MAX_JSON_DEPTH} levels"));
+    }
+
+    serde_json::from_str(json).map_err(|parse_error| parse_error.to_string())
+}
+
+/// Whether the objects and arrays in "json" nest deeper than "max_depth", ignoring brackets within strings
+fn exceeds_json_depth(json: &str, max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for byte in json.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "[[...]]" nested "depth" levels deep
+    fn nested(depth: usize) -> String {
+        "[".repeat(depth) + &"]".repeat(depth)
+    }
+
+    #[test]
+    fn json_depth_limit_boundary() {
+        assert!(from_json_limited::<serde_json::Value>(&nested(MAX_JSON_DEPTH)).is_ok());
+        assert!(from_json_limited::<serde_json::Value>(&nested(MAX_JSON_DEPTH + 1)).is_err());
+    }
+
+    #[test]
+    fn pathologically_nested_json_is_rejected_quickly() {
+        let started = Instant::now();
+        let unclosed = "[".repeat(MAX_MESSAGE_SIZE);
+        assert!(from_json_limited::<serde_json::Value>(&unclosed).is_err());
+        assert!(from_json_limited::<serde_json::Value>(&nested(MAX_MESSAGE_SIZE / 2)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn oversized_json_is_rejected() {
+        let oversized = format!("\"{}\"", "a".repeat(MAX_MESSAGE_SIZE));
+        assert!(from_json_limited::<String>(&oversized).is_err());
+    }
+
+    #[test]
+    fn brackets_in_strings_are_not_nesting() {
+        let json = format!("[\"{}\", \"\\\"[\"]", "[".repeat(MAX_JSON_DEPTH * 2));
+        assert!(from_json_limited::<Vec<String>>(&json).is_ok());
+    }
+
+    #[test]
+    fn invite_link_round_trip() {
+        let invite = Invite{ server: "wss://chat.example:5050".to_string(), room: "dev & ops".to_string(), token: Some("a/b c".to_string()) };
+        assert_eq!(Invite::parse_link(&invite.to_link()), Ok(invite));
+    }
+
+    #[test]
+    fn malformed_invite_links_are_rejected() {
+        for link in [
+            "https://join?server=ws%3A%2F%2Fhost&room=dev",
+            "chatey://join?room=dev",
+            "chatey://join?server=&room=dev",
+            "chatey://join?server=ws%3A%2F%2Fhost",
+            "chatey://join?server=ws%3A%2F%2Fhost&room",
+            "chatey://join?server=ws%3A%2F%2Fhost&room=dev&color=red",
+            "chatey://join?server=ws%3A%2F%2Fhost&room=%E",
+            "chatey://join?server=ws%3A%2F%2Fhost&room=%FF",
+        ] {
+            assert!(Invite::parse_link(link).is_err(), "{link}");
+        }
+    }
+
+    /// A chat message from "bob", with whatever "timestamp" is as its timestamp
+    fn with_timestamp(timestamp: &str) -> ClientMessage {
+        let json = format!(r#"{{"input_message": "hi", "from_username": "bob", "timestamp": {timestamp}}}"#);
+        from_json_limited(&json).unwrap()
+    }
+
+    fn is_about_now(timestamp: Instant) -> bool {
+        timestamp.elapsed() < Duration::from_secs(1)
+    }
+
+    #[test]
+    fn missing_timestamp_is_now() {
+        let message: ClientMessage = from_json_limited(r#"{"input_message": "hi", "from_username": "bob"}"#).unwrap();
+        assert!(is_about_now(message.get_timestamp()));
+    }
+
+    #[test]
+    fn malformed_timestamps_are_now() {
+        for timestamp in [r#""yesterday""#, "-5", "1.5", "null", r#"{"secs": 3}"#, "18446744073709551615"] {
+            let message = with_timestamp(timestamp);
+            assert!(is_about_now(message.get_timestamp()), "{timestamp}");
+            assert_eq!(message.get_message(), "hi");
+        }
+    }
+
+    #[test]
+    fn past_and_future_timestamps_are_kept() {
+        let millis_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+
+        let past = with_timestamp(&(millis_now - 60_000).to_string()).get_timestamp();
+        assert!((59..=61).contains(&past.elapsed().as_secs()));
+
+        let future = with_timestamp(&(millis_now + 60_000).to_string()).get_timestamp();
+        let ahead = future.saturating_duration_since(Instant::now());
+        assert!((59..=61).contains(&ahead.as_secs()));
+    }
+
+    fn chat_message() -> ChatMessage {
+        ChatMessage::builder()
+            .from("127.0.0.1:5000".parse().unwrap())
+            .username("bob".to_string())
+            .body("hi \"there\"\nall".to_string())
+            .kind(MessageKind::Table)
+            .room("dev".to_string())
+            .expires_in(Duration::from_millis(1500))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn client_message_round_trip() {
+        let sent = ClientMessage::from(chat_message());
+        let received: ClientMessage = from_json_limited(&serde_json::to_string(&sent).unwrap()).unwrap();
+
+        assert_eq!(received.get_username(), "bob");
+        assert_eq!(received.get_message(), "hi \"there\"\nall");
+        assert_eq!(received.get_kind(), MessageKind::Table);
+        assert_eq!(received.get_room().as_deref(), Some("dev"));
+        assert_eq!(received.expires_in_secs, Some(2));
+        let drift = received.get_timestamp().max(sent.get_timestamp()) - received.get_timestamp().min(sent.get_timestamp());
+        assert!(drift < Duration::from_millis(5));
+    }
+
+    #[test]
+    fn display_shows_every_field() {
+        let chat_message = chat_message();
+        assert_eq!(
+            chat_message.to_string(),
+            r#""bob" (127.0.0.1:5000): "hi \"there\"\nall" (Table in #dev, 0 s ago, disappears after 1.5 s)"#
+        );
+        assert_eq!(
+            ClientMessage::from(chat_message).to_string(),
+            r#""bob": "hi \"there\"\nall" (Table in #dev, 0 s ago, disappears after 2 s)"#
+        );
+        assert_eq!(ClientMessage::new("bob".to_string(), "hi".to_string()).to_string(), r#""bob": "hi" (Chat, 0 s ago)"#);
+    }
+}